@@ -0,0 +1 @@
+ALTER TABLE items DROP COLUMN app_metadata;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE items ADD COLUMN app_metadata VARCHAR;
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE health_check
\ No newline at end of file
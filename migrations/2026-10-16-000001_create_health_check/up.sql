@@ -0,0 +1,4 @@
+CREATE TABLE health_check (
+    id INTEGER PRIMARY KEY NOT NULL,
+    checked_at DATETIME NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN last_sync;
\ No newline at end of file
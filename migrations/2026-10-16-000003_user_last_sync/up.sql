@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN last_sync DATETIME;
\ No newline at end of file
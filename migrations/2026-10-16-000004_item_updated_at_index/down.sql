@@ -0,0 +1 @@
+DROP INDEX index_item_owner_updated_at_20261016000004;
\ No newline at end of file
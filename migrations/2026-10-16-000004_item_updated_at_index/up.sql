@@ -0,0 +1 @@
+CREATE INDEX index_item_owner_updated_at_20261016000004 ON items(owner, updated_at);
\ No newline at end of file
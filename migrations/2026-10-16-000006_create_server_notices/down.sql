@@ -0,0 +1,2 @@
+DROP TABLE notice_acks;
+DROP TABLE server_notices
\ No newline at end of file
@@ -0,0 +1,14 @@
+CREATE TABLE server_notices (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    message VARCHAR NOT NULL,
+    created_at DATETIME NOT NULL
+);
+CREATE TABLE notice_acks (
+    notice_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY (notice_id, user_id),
+    FOREIGN KEY (notice_id)
+        REFERENCES server_notices (id),
+    FOREIGN KEY (user_id)
+        REFERENCES users (id)
+)
\ No newline at end of file
@@ -0,0 +1,3 @@
+DROP INDEX index_item_owner_user_seq_20261016000007;
+ALTER TABLE users DROP COLUMN last_item_seq;
+ALTER TABLE items DROP COLUMN user_seq;
\ No newline at end of file
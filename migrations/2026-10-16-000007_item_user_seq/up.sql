@@ -0,0 +1,12 @@
+ALTER TABLE items ADD COLUMN user_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN last_item_seq BIGINT NOT NULL DEFAULT 0;
+-- Number existing items of each user in the order of their global ID,
+-- which is the order they were saved in
+UPDATE items SET user_seq = (
+    SELECT COUNT(*) FROM items AS prev
+    WHERE prev.owner = items.owner AND prev.id <= items.id
+);
+UPDATE users SET last_item_seq = (
+    SELECT COALESCE(MAX(user_seq), 0) FROM items WHERE items.owner = users.id
+);
+CREATE UNIQUE INDEX index_item_owner_user_seq_20261016000007 ON items(owner, user_seq)
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE large_contents
\ No newline at end of file
@@ -0,0 +1,8 @@
+CREATE TABLE large_contents (
+    owner INTEGER NOT NULL,
+    uuid VARCHAR NOT NULL,
+    content VARCHAR NOT NULL,
+    PRIMARY KEY (owner, uuid),
+    FOREIGN KEY (owner)
+        REFERENCES users (id)
+)
\ No newline at end of file
@@ -1,10 +1,18 @@
 use crate::DbConn;
+use crate::config::Config;
 use crate::user;
 use crate::item;
 use crate::lock::UserLock;
+use crate::metrics::Metrics;
+use crate::notice::Notice;
+use crate::ratelimit::{ClientIp, RegistrationLimiter};
+use crate::sync_tokens::DecryptionFailures;
 use itertools::{Itertools, Either};
-use rocket::State;
-use rocket::http::Status;
+use rocket::{Data, State};
+use rocket::handler::{self, Handler};
+use rocket::http::{Accept, ContentType, Status};
+use rocket::request::{LenientForm, Request};
+use rocket::response::{self, Content, Responder};
 use rocket::response::status::Custom;
 use rocket_contrib::json::Json;
 use serde::{Serialize, Deserialize};
@@ -18,12 +26,32 @@ lazy_static! {
 
 pub fn routes() -> impl Into<Vec<rocket::Route>> {
     routes![
+        index,
+        healthz,
+        version,
+        metrics,
+        openapi,
         auth,
         auth_change_pw,
+        auth_rotate,
+        auth_delete,
         auth_sign_in,
         auth_params,
         auth_ping,
-        items_sync
+        auth_me,
+        items_sync,
+        items_search,
+        items_export,
+        items_keys,
+        items_exists,
+        items_purge,
+        notices_ack,
+        admin_suspend,
+        admin_restore,
+        admin_notices,
+        admin_users,
+        admin_user_items,
+        admin_locks
     ]
 }
 
@@ -31,24 +59,233 @@ pub fn routes() -> impl Into<Vec<rocket::Route>> {
 #[serde(untagged)]
 enum Response<T: Serialize> {
     Error {
-        errors: Vec<String>
+        errors: Vec<String>,
+        // Machine-readable, e.g. `unauthorized` or `reset_sync`
+        code: String,
+        // The same errors by field, when a request fails validation
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        field_errors: Vec<user::FieldError>
     },
     Success(T)
 }
 
+// Like Json from rocket_contrib, but indented if PRETTY_JSON is set
+// or the request has a `pretty` query parameter, for reading by hand
+pub struct PrettyJson<T>(pub T);
+
+fn wants_pretty_json(req: &Request) -> bool {
+    let from_query = req.uri().query()
+        .map(|q| q.split('&').any(|kv| kv == "pretty" || kv == "pretty=true" || kv == "pretty=1"))
+        .unwrap_or(false);
+    from_query || req.guard::<State<Config>>().succeeded()
+        .map(|config| config.pretty_json)
+        .unwrap_or(false)
+}
+
+impl<'r, T: Serialize> Responder<'r> for PrettyJson<T> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let body = if wants_pretty_json(req) {
+            serde_json::to_string_pretty(&self.0)
+        } else {
+            serde_json::to_string(&self.0)
+        };
+        body.map(|body| Content(ContentType::JSON, body))
+            .map_err(|e| {
+                eprintln!("Cannot serialize response: {}", e);
+                Status::InternalServerError
+            })?
+            .respond_to(req)
+    }
+}
+
 // Some shorthands
-type JsonResp<T> = Json<Response<T>>;
+type JsonResp<T> = PrettyJson<Response<T>>;
 
 fn success_resp<T: Serialize>(resp: T) -> Custom<JsonResp<T>> {
-    Custom(Status::Ok, Json(Response::Success(resp)))
+    Custom(Status::Ok, PrettyJson(Response::Success(resp)))
 }
 
+// The error code defaults to the reason phrase of the status,
+// e.g. `Too Many Requests` becomes `too_many_requests`
 fn error_resp<T: Serialize>(status: Status, errors: Vec<String>) -> Custom<JsonResp<T>> {
-    Custom(status, Json(Response::Error {
-        errors
+    let code = status.reason.to_lowercase().replace(' ', "_");
+    error_resp_with_code(status, &code, errors)
+}
+
+fn error_resp_with_code<T: Serialize>(status: Status, code: &str, errors: Vec<String>) -> Custom<JsonResp<T>> {
+    Custom(status, PrettyJson(Response::Error {
+        errors,
+        code: code.into(),
+        field_errors: vec![]
+    }))
+}
+
+// 400 listing every problem with the fields of a request
+fn field_errors_resp<T: Serialize>(field_errors: Vec<user::FieldError>) -> Custom<JsonResp<T>> {
+    Custom(Status::BadRequest, PrettyJson(Response::Error {
+        errors: field_errors.iter().map(|e| e.error.clone()).collect(),
+        code: "bad_request".into(),
+        field_errors
     }))
 }
 
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![
+        unauthorized,
+        forbidden,
+        too_many_requests,
+        internal_server_error,
+        service_unavailable
+    ]
+}
+
+// Wraps a route handler so that a panic inside it fails the request
+// with a 500 (rendered by our catcher) instead of dropping the connection
+// The panic message itself is already logged by the default panic hook
+#[derive(Clone)]
+struct CatchPanic(Box<dyn Handler>);
+
+impl Handler for CatchPanic {
+    fn handle<'r>(&self, req: &'r Request, data: Data) -> handler::Outcome<'r> {
+        let handler = &self.0;
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler.handle(req, data)))
+            .unwrap_or_else(|_| {
+                eprintln!("Handler panicked while serving {} {}", req.method(), req.uri());
+                handler::Outcome::Failure(Status::InternalServerError)
+            })
+    }
+}
+
+pub fn catch_panics(routes: impl Into<Vec<rocket::Route>>) -> Vec<rocket::Route> {
+    routes.into().into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(CatchPanic(route.handler.clone()));
+            route
+        })
+        .collect()
+}
+
+// Request guards cannot hand their errors over to catchers,
+// so they leave them in the request-local cache instead
+pub struct GuardError {
+    pub error: String,
+    // Overrides the code derived from the status
+    pub code: Option<&'static str>
+}
+
+fn guard_error_resp(status: Status, req: &Request) -> Custom<JsonResp<()>> {
+    let err = req.local_cache(|| GuardError {
+        error: status.reason.into(),
+        code: None
+    });
+    match err.code {
+        Some(code) => error_resp_with_code(status, code, vec![err.error.clone()]),
+        None => error_resp(status, vec![err.error.clone()])
+    }
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> Custom<JsonResp<()>> {
+    guard_error_resp(Status::Unauthorized, req)
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> Custom<JsonResp<()>> {
+    guard_error_resp(Status::Forbidden, req)
+}
+
+#[catch(429)]
+fn too_many_requests(req: &Request) -> Custom<JsonResp<()>> {
+    guard_error_resp(Status::TooManyRequests, req)
+}
+
+#[catch(500)]
+fn internal_server_error(req: &Request) -> Custom<JsonResp<()>> {
+    guard_error_resp(Status::InternalServerError, req)
+}
+
+#[catch(503)]
+fn service_unavailable(req: &Request) -> Custom<JsonResp<()>> {
+    guard_error_resp(Status::ServiceUnavailable, req)
+}
+
+#[derive(Serialize)]
+struct IndexResp {
+    server: String,
+    status: String
+}
+
+#[derive(Responder)]
+enum IndexResponse {
+    Json(Custom<JsonResp<IndexResp>>),
+    Text(Content<String>)
+}
+
+// A tiny status, mostly for uptime checkers
+// JSON for those asking for it, plain text for browsers and everyone else
+#[get("/")]
+fn index(config: State<Config>, accept: Option<&Accept>) -> IndexResponse {
+    let wants_json = accept
+        .map(|a| a.preferred().media_type().is_json())
+        .unwrap_or(false);
+    if wants_json {
+        IndexResponse::Json(success_resp(IndexResp {
+            server: config.server_name.clone(),
+            status: "ok".into()
+        }))
+    } else {
+        IndexResponse::Text(Content(ContentType::Plain, format!("{}: ok\n", config.server_name)))
+    }
+}
+
+#[derive(Serialize)]
+struct HealthResp {
+    read_ok: bool,
+    write_ok: bool
+}
+
+// Database health for load balancers
+// SQLite can stay readable while writes fail, so both are checked
+#[get("/healthz")]
+fn healthz(db: DbConn) -> Custom<PrettyJson<HealthResp>> {
+    let resp = HealthResp {
+        read_ok: crate::db::check_read(&db.0).is_ok(),
+        write_ok: crate::db::check_write(&db.0).is_ok()
+    };
+    let status = if resp.read_ok && resp.write_ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    Custom(status, PrettyJson(resp))
+}
+
+#[derive(Serialize)]
+struct VersionResp {
+    server: String,
+    version: String
+}
+
+#[get("/metrics")]
+fn metrics(metrics: State<Metrics>) -> Content<String> {
+    Content(ContentType::with_params("text", "plain", ("version", "0.0.4")), metrics.render())
+}
+
+#[get("/version")]
+fn version(config: State<Config>) -> Custom<JsonResp<VersionResp>> {
+    success_resp(VersionResp {
+        server: config.server_name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string()
+    })
+}
+
+// A hand-written description of this API, for client developers
+// Please keep src/openapi.json updated when touching the routes
+#[get("/openapi.json")]
+fn openapi() -> Content<&'static str> {
+    Content(ContentType::JSON, include_str!("openapi.json"))
+}
+
 #[derive(Serialize)]
 struct AuthResultUser {
     email: String,
@@ -61,16 +298,73 @@ struct AuthResult {
     token: String
 }
 
+// Meant for every endpoint that stores a new email address
+fn validate_email(config: &Config, email: &str) -> Result<(), user::FieldError> {
+    if email.len() > config.max_email_length {
+        Err(user::FieldError::new("email", "email_too_long",
+            format!("Email address must not be longer than {} characters", config.max_email_length)))
+    } else if !EMAIL_RE.is_match(email) {
+        Err(user::FieldError::new("email", "invalid_email", "Invalid email address"))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_new_user(config: &Config, new_user: &user::NewUser) -> Result<(), Vec<user::FieldError>> {
+    let key_params = user::KeyParams {
+        pw_cost: Some(new_user.pw_cost),
+        pw_nonce: Some(new_user.pw_nonce.clone()),
+        version: Some(new_user.version.clone())
+    };
+    let errors = validate_email(config, &new_user.email).err().into_iter()
+        .chain(validate_new_password(&new_user.password, &key_params).err().unwrap_or_default())
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn seed_welcome_item(db: &impl crate::SqliteLike, config: &Config, email: &str, content: &str) -> Result<(), String> {
+    let u = user::User::find_user_by_email(db, email).map_err(|e| e.0)?;
+    item::SyncItem::items_insert(db, &u, &item::SyncItem::new_note(content),
+        config.inline_content_max_bytes)
+        .map(|_| ())
+        .map_err(|e| e.0)
+}
+
 #[post("/auth", format = "json", data = "<new_user>")]
-fn auth(db: DbConn, new_user: Json<user::NewUser>) -> Custom<JsonResp<AuthResult>> {
-    if !EMAIL_RE.is_match(&new_user.email) {
-        return error_resp(Status::BadRequest, vec!["Invalid email address".into()]);
+fn auth(
+    db: DbConn, config: State<Config>, limiter: State<RegistrationLimiter>,
+    ip: ClientIp, new_user: Json<user::NewUser>
+) -> Custom<JsonResp<AuthResult>> {
+    if let Err(errors) = validate_new_user(&config, &new_user) {
+        return field_errors_resp(errors);
+    }
+
+    // Counted before the account is created, so that
+    // concurrent registrations cannot all get in
+    if !limiter.try_record(&ip) {
+        return error_resp(Status::TooManyRequests,
+            vec!["Too many registrations from this address, please try again later".into()]);
     }
 
     match user::User::create(&db.0, &new_user) {
-        Ok(_) => _sign_in(db, &new_user.email, &new_user.password),
-        Err(user::UserOpError(e)) =>
+        Ok(_) => {
+            if let Some(ref welcome) = config.seed_welcome_item {
+                // The account exists by now, so a failure here
+                // should not fail the registration
+                if let Err(e) = seed_welcome_item(&db.0, &config, &new_user.email, welcome) {
+                    eprintln!("Cannot seed welcome item for {}: {}", new_user.email, e);
+                }
+            }
+            _sign_in(db, &config, &new_user.email, &new_user.password)
+        },
+        Err(user::UserOpError(e)) => {
+            limiter.forget(&ip);
             error_resp(Status::InternalServerError, vec![e])
+        }
     }
 }
 
@@ -81,16 +375,27 @@ struct SignInParams {
 }
 
 #[post("/auth/sign_in", format = "json", data = "<params>")]
-fn auth_sign_in(db: DbConn, params: Json<SignInParams>) -> Custom<JsonResp<AuthResult>> {
-    _sign_in(db, &params.email, &params.password)
+fn auth_sign_in(db: DbConn, config: State<Config>, params: Json<SignInParams>) -> Custom<JsonResp<AuthResult>> {
+    _sign_in(db, &config, &params.email, &params.password)
 }
 
 // Shared logic for all interfaces that needs to do an automatic sign-in
-fn _sign_in(db: DbConn, mail: &str, passwd: &str) -> Custom<JsonResp<AuthResult>> {
+fn _sign_in(db: DbConn, config: &Config, mail: &str, passwd: &str) -> Custom<JsonResp<AuthResult>> {
     // Try to find the user first
-    let res = user::User::find_user_by_email(&db.0, mail)
-                .and_then(|u| u.create_token(&db.0, passwd)
-                                .map(|x| (u.uuid, u.email, x)));
+    let u = match user::User::find_user_by_email(&db.0, mail) {
+        Ok(u) => u,
+        Err(user::UserOpError(e)) =>
+            return error_resp(Status::InternalServerError, vec![e])
+    };
+
+    // Only tell those who know the password that an account is suspended
+    if u.suspended && u.password == passwd {
+        return error_resp_with_code(Status::Forbidden, user::ACCOUNT_SUSPENDED,
+            vec!["Account suspended".into()]);
+    }
+
+    let res = u.create_token(&db.0, passwd, config.max_sessions_per_user)
+                .map(|x| (u.uuid, u.email, x));
     match res {
         Ok((uuid, email, token)) => success_resp(AuthResult {
             user: AuthResultUser {
@@ -134,74 +439,329 @@ fn auth_params(db: DbConn, email: String) -> Custom<JsonResp<AuthParams>> {
 struct ChangePwParams {
     email: String,
     password: String,
-    current_password: String
+    current_password: String,
+    // Optionally update the key derivation parameters at the same time
+    pw_cost: Option<i32>,
+    pw_nonce: Option<String>,
+    version: Option<String>
+}
+
+fn validate_new_password(passwd: &str, key_params: &user::KeyParams) -> Result<(), Vec<user::FieldError>> {
+    let errors = user::validate_password(passwd).err().into_iter()
+        .chain(key_params.validate().err().unwrap_or_default())
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 #[post("/auth/change_pw", format = "json", data = "<params>")]
 fn auth_change_pw(db: DbConn, params: Json<ChangePwParams>) -> Custom<JsonResp<()>> {
-    let res = user::User::find_user_by_email(&db.0, &params.email)
+    let ChangePwParams {
+        email, password, current_password,
+        pw_cost, pw_nonce, version
+    } = params.into_inner();
+    let key_params = user::KeyParams {
+        pw_cost,
+        pw_nonce,
+        version
+    };
+    if let Err(errors) = validate_new_password(&password, &key_params) {
+        return field_errors_resp(errors);
+    }
+
+    let res = user::User::find_user_by_email(&db.0, &email)
                 .and_then(|u|
-                    u.change_pw(&db.0, &params.current_password, &params.password));
+                    u.change_pw(&db.0, &current_password, &password, &key_params));
     match res {
-        Ok(_) => Custom(Status::NoContent, Json(Response::Success(()))),
+        Ok(_) => Custom(Status::NoContent, PrettyJson(Response::Success(()))),
+        Err(user::UserOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct RotateParams {
+    current_password: String,
+    password: String,
+    pw_cost: i32,
+    pw_nonce: String,
+    version: String
+}
+
+// Change the password along with all key parameters and revoke every
+// existing session, returning a new one for the calling client
+#[post("/auth/rotate", format = "json", data = "<params>")]
+fn auth_rotate(
+    db: DbConn, config: State<Config>, u: user::User, params: Json<RotateParams>
+) -> Custom<JsonResp<AuthResult>> {
+    let RotateParams {
+        current_password, password,
+        pw_cost, pw_nonce, version
+    } = params.into_inner();
+    // Not a 401, which would sign the client out
+    if u.password != current_password.as_str() {
+        return error_resp(Status::Forbidden, vec!["Password mismatch".into()]);
+    }
+
+    let key_params = user::KeyParams {
+        pw_cost: Some(pw_cost),
+        pw_nonce: Some(pw_nonce),
+        version: Some(version)
+    };
+    if let Err(errors) = validate_new_password(&password, &key_params) {
+        return field_errors_resp(errors);
+    }
+
+    match u.rotate(&db.0, &password, &key_params) {
+        Ok(_) => _sign_in(db, &config, &u.email, &password),
+        Err(user::UserOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteParams {
+    password: String
+}
+
+// Delete the account of the calling user
+// Its data is kept for ACCOUNT_RETENTION_DAYS, in case an admin
+// has to restore it, and the account stays suspended until then
+#[post("/auth/delete", format = "json", data = "<params>")]
+fn auth_delete(db: DbConn, u: user::User, params: Json<DeleteParams>) -> Custom<JsonResp<()>> {
+    if u.password != params.password.as_str() {
+        return error_resp(Status::Forbidden, vec!["Password mismatch".into()]);
+    }
+
+    match u.soft_delete(&db.0) {
+        Ok(_) => Custom(Status::NoContent, PrettyJson(Response::Success(()))),
         Err(user::UserOpError(e)) =>
             error_resp(Status::InternalServerError, vec![e])
     }
 }
 
+// Timestamps from the database are naive UTC
+fn format_timestamp(t: chrono::NaiveDateTime) -> String {
+    chrono::TimeZone::from_utc_datetime(&chrono::Utc, &t)
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[derive(Serialize)]
+struct MeResp {
+    uuid: String,
+    email: String,
+    last_sync: Option<String>
+}
+
+#[get("/auth/me")]
+fn auth_me(_db: DbConn, u: user::User) -> Custom<JsonResp<MeResp>> {
+    success_resp(MeResp {
+        uuid: u.uuid,
+        email: u.email,
+        last_sync: u.last_sync.map(format_timestamp)
+    })
+}
+
 // For testing the User request guard
 #[get("/auth/ping")]
 fn auth_ping(_db: DbConn, u: user::User) -> Custom<JsonResp<String>> {
-    Custom(Status::Ok, Json(Response::Success(u.email)))
+    Custom(Status::Ok, PrettyJson(Response::Success(u.email)))
 }
 
-#[derive(Deserialize)]
+// Page size used when a client asks for a non-positive limit
+// (this is what the official clients ask for by default)
+pub(crate) const DEFAULT_ITEMS_PER_SYNC: i64 = 150;
+// Upper bound of items retrieved in one sync, no matter what the client asks for
+// Clients are expected to continue with cursor_token as usual.
+pub(crate) const MAX_ITEMS_PER_SYNC: i64 = 1000;
+
+// Clamp the limit passed by the client into [1, MAX_ITEMS_PER_SYNC]
+// No limit at all is still allowed, for compatibility
+pub(crate) fn clamp_sync_limit(limit: Option<i64>) -> Option<i64> {
+    limit.map(|l| if l <= 0 {
+        DEFAULT_ITEMS_PER_SYNC
+    } else {
+        l.min(MAX_ITEMS_PER_SYNC)
+    })
+}
+
+// Returned with `410 Gone` when the client should throw away its
+// sync_token and perform a full sync from scratch
+const RESET_SYNC_ERROR: &str = "sync_token is no longer valid, please reset it and sync again";
+
+fn reset_sync_resp<T: Serialize>() -> Custom<JsonResp<T>> {
+    error_resp_with_code(Status::Gone, "reset_sync", vec![RESET_SYNC_ERROR.into()])
+}
+
+// The user_seq of `u` a sync_token or cursor points to, None if it is invalid
+// Tokens issued before user_seq existed carry a global ID instead, which
+// is translated into the user_seq the client had seen by then
+fn token_to_seq(db: &DbConn, u: &user::User, token: &str) -> Result<Option<i64>, String> {
+    use crate::sync_tokens::SyncPosition;
+    match crate::sync_tokens::token_to_position(token) {
+        Err(_) => Ok(None),
+        Ok(SyncPosition::Seq(seq)) => Ok(Some(seq)),
+        Ok(SyncPosition::LegacyId(id)) => item::SyncItem::seq_of_legacy_id(&db.0, u, id)
+            .map_err(|item::ItemOpError(e)| e)
+    }
+}
+
+// Like token_to_seq, for the sync_token or cursor_token (`name`) of a sync,
+// returning the response to give instead if it cannot be used
+// Tokens that cannot be decrypted are bad requests, unless they come in a
+// cluster (see DecryptionFailures); then SYNC_TOKEN_SECRET has most likely
+// been changed, and the only way forward for clients is a full sync.
+fn sync_token_to_seq<T: Serialize>(
+    db: &DbConn, failures: &DecryptionFailures, u: &user::User, name: &str, token: &str
+) -> Result<i64, Custom<JsonResp<T>>> {
+    use crate::sync_tokens::{SyncPosition, TokenError};
+    let position = match crate::sync_tokens::token_to_position(token) {
+        Err(TokenError::Malformed) =>
+            return Err(error_resp(Status::BadRequest, vec![format!("Invalid {}", name)])),
+        Err(TokenError::Undecryptable) => {
+            if failures.record(u.id) {
+                return Err(reset_sync_resp());
+            }
+            return Err(error_resp(Status::BadRequest, vec![format!("Invalid {}", name)]));
+        },
+        Ok(position) => position
+    };
+    match position {
+        SyncPosition::Seq(seq) => Ok(seq),
+        SyncPosition::LegacyId(id) => match item::SyncItem::seq_of_legacy_id(&db.0, u, id) {
+            Ok(Some(seq)) => Ok(seq),
+            // Ahead of anything we have (see items_sync)
+            Ok(None) => Err(reset_sync_resp()),
+            Err(item::ItemOpError(e)) => Err(error_resp(Status::InternalServerError, vec![e]))
+        }
+    }
+}
+
+// Convert items from the database for a response
+fn to_sync_items(config: &Config, items: Vec<item::Item>) -> Vec<item::SyncItem> {
+    items.into_iter()
+        .map(|x| {
+            let mut it: item::SyncItem = x.into();
+            // Stored types are never rewritten, only what we send out
+            if let Some(alias) = config.content_type_aliases.get(&it.content_type) {
+                it.content_type = alias.clone();
+            }
+            if config.normalize_timestamps {
+                it.normalize_timestamps()
+            } else {
+                it
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
 struct SyncParams {
     items: Vec<item::SyncItem>,
     sync_token: Option<String>,
     cursor_token: Option<String>,
-    limit: Option<i64>
+    limit: Option<i64>,
+    // Whether to report how many items are left after this page
+    #[serde(default)]
+    return_remaining: bool,
+    // Merged versions of items that conflicted in an earlier sync.
+    // These are saved as-is without conflict detection, since the
+    // client has already seen the server version while merging.
+    #[serde(default)]
+    resolve_conflicts: Vec<item::SyncItem>,
+    // Clients that already have what they sent can skip `saved_items`
+    #[serde(default = "default_true")]
+    return_saved: bool,
+    // Only retrieve items updated after this time, for reconciliation
+    // (paging with sync_token and cursor_token works as usual)
+    updated_after: Option<String>,
+    // Leave content and enc_item_key out of retrieved_items,
+    // e.g. for building a list of items
+    #[serde(default)]
+    metadata_only: bool
 }
 
-#[derive(Serialize)]
-struct SyncConflict {
+fn default_true() -> bool {
+    true
+}
+
+// Conflict type for items refused due to MIN_ITEM_UPDATE_INTERVAL
+const UPDATE_THROTTLED: &str = "update_throttled";
+
+#[derive(Serialize, Clone)]
+pub(crate) struct SyncConflict {
     #[serde(rename(serialize = "type"))]
-    conf_type: String,
-    server_item: Option<item::SyncItem>,
-    unsaved_item: Option<item::SyncItem>
+    pub(crate) conf_type: String,
+    pub(crate) server_item: Option<item::SyncItem>,
+    pub(crate) unsaved_item: Option<item::SyncItem>
 }
 
 impl SyncConflict {
-    fn uuid<'a>(&self) -> String {
-        if let Some(ref item) = self.server_item {
-            item.uuid.clone()
-        } else if let Some(ref item) = self.unsaved_item {
-            item.uuid.clone()
-        } else {
-            panic!("SyncConflict should have either server_item or unsaved_item");
-        }
+    // A conflict should carry at least one of the items,
+    // but one without any simply matches no uuid at all
+    pub(crate) fn uuid(&self) -> Option<&str> {
+        self.server_item.as_ref()
+            .or(self.unsaved_item.as_ref())
+            .map(|item| item.uuid.as_str())
     }
 }
 
-#[derive(Serialize)]
-struct SyncResp {
+#[derive(Serialize, Clone)]
+pub(crate) struct SyncResp {
     retrieved_items: Vec<item::SyncItem>,
-    saved_items: Vec<item::SyncItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saved_items: Option<Vec<item::SyncItem>>, // omitted if `return_saved` is false
     conflicts: Vec<SyncConflict>,
     sync_token: Option<String>, // for convenience, we will actually always return this
-    cursor_token: Option<String>
+    cursor_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining: Option<i64>, // only if `return_remaining` was requested
+    unsaved: Vec<UnsavedItem>, // items that could not be saved, and why
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    notices: Vec<NoticeResp> // from the operators, until acknowledged
+}
+
+#[derive(Serialize, Clone)]
+struct NoticeResp {
+    id: i32,
+    message: String,
+    created_at: String
+}
+
+impl From<Notice> for NoticeResp {
+    fn from(n: Notice) -> NoticeResp {
+        NoticeResp {
+            id: n.id,
+            message: n.message,
+            created_at: format_timestamp(n.created_at)
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct UnsavedItem {
+    uuid: String,
+    error: String
 }
 
+pub(crate) type SyncDedup = crate::dedup::SyncDedup<SyncResp>;
+
 #[post("/items/sync", format = "json", data = "<params>")]
+#[allow(clippy::too_many_arguments)]
 fn items_sync(
-    db: DbConn, lock: State<UserLock>,
+    db: DbConn, lock: State<UserLock>, config: State<Config>,
+    metrics: State<Metrics>, dedup: State<SyncDedup>,
+    failures: State<DecryptionFailures>,
     u: user::User, params: Json<SyncParams>
 ) -> Custom<JsonResp<SyncResp>> {
     // Only allow one sync per user at the same time
     // Operations below are far from atomic (neither are they in Ruby or Go impl)
     // so allowing multiple synchronize sessions each time can cause
     // some confusing behavior, e.g. another sync session might insert
-    // something new into the database after this one gets the current_max_id
+    // something new into the database after this one gets the current_max_seq
     // but before this one returns. It can also mess things up during
     // insertions into the database.
     // In short, do not let the same user synchronize from two clients
@@ -209,46 +769,89 @@ fn items_sync(
     // and at any given point in time, up to one sync process is running
     // for each user.
     let mutex = lock.get_mutex(u.id);
-    let _lock = mutex.lock().unwrap();
+    let _lock = lock.lock(&mutex);
 
-    // sync_token should always be set to the maximum ID currently available
+    // A retry of the last sync (e.g. after the response got lost) is answered
+    // the same way again, and does not save anything twice
+    let payload_hash = dedup.payload_hash(&*params);
+    if let Some(ref hash) = payload_hash {
+        if let Some(resp) = dedup.lookup(u.id, hash) {
+            return success_resp(resp);
+        }
+    }
+
+    // sync_token should always be set to the maximum user_seq currently available
     // (for this user, of course)
     // Remember that we have a mutex at the beginning of this function,
-    // so all that can change the current_max_id for the current user
+    // so all that can change the current_max_seq for the current user
     // is operations later in this function.
-    let new_sync_token = match item::SyncItem::get_current_max_id(&db.0, &u) {
-        Ok(Some(id)) => Some(crate::sync_tokens::max_id_to_token(id)),
-        Ok(None) => None,
+    let current_max_seq = match item::SyncItem::get_current_max_seq(&db.0, &u) {
+        Ok(seq) => seq,
         Err(item::ItemOpError(e)) =>
             return error_resp(Status::InternalServerError, vec![e])
     };
+    let new_sync_token = current_max_seq.map(crate::sync_tokens::seq_to_token);
 
+    let inner_params = params.into_inner();
     let mut resp = SyncResp {
         retrieved_items: vec![],
-        saved_items: vec![],
+        saved_items: if inner_params.return_saved { Some(vec![]) } else { None },
         conflicts: vec![],
         sync_token: new_sync_token,
-        cursor_token: None
+        cursor_token: None,
+        remaining: None,
+        unsaved: vec![],
+        notices: vec![]
     };
 
-    let inner_params = params.into_inner();
+    let limit = clamp_sync_limit(inner_params.limit);
+    let updated_after = match timestamp_param("updated_after", inner_params.updated_after) {
+        Ok(t) => t,
+        Err(e) => return error_resp(Status::BadRequest, vec![e])
+    };
+    // Conflicts are only detected against retrieved items, so saving
+    // alongside a filtered retrieval could overwrite changes made elsewhere
+    if updated_after.is_some()
+            && (!inner_params.items.is_empty() || !inner_params.resolve_conflicts.is_empty()) {
+        return error_resp(Status::BadRequest,
+            vec!["updated_after cannot be combined with items to save".into()]);
+    }
+
+    // A client with a badly wrong clock would mess up ordering by timestamps
+    // (for everyone sorting by them, including its other devices)
+    if let Some(skew) = config.max_clock_skew_secs {
+        let latest = chrono::Utc::now() + chrono::Duration::seconds(skew);
+        let future_items = inner_params.items.iter()
+            .chain(inner_params.resolve_conflicts.iter())
+            .filter(|it| it.is_from_future(latest))
+            .map(|it| format!("Item {} is timestamped too far in the future", it.uuid))
+            .collect::<Vec<_>>();
+        if !future_items.is_empty() {
+            return error_resp(Status::BadRequest, future_items);
+        }
+    }
 
-    let from_id: Option<i64> = if let Some(cursor_token) = inner_params.cursor_token {
+    let from_seq: Option<i64> = if let Some(cursor_token) = inner_params.cursor_token {
         // If the client provides cursor_token,
         // then, we return all records
         // until sync_token (the head of the last sync)
-        match crate::sync_tokens::token_to_max_id(&cursor_token) {
-            Err(()) =>
-                return error_resp(Status::InternalServerError, vec!["Invalid cursor_token".into()]),
-            Ok(id) => Some(id)
+        match sync_token_to_seq(&db, &failures, &u, "cursor_token", &cursor_token) {
+            Err(resp) => return resp,
+            Ok(seq) => Some(seq)
         }
     } else if let Some(sync_token) = inner_params.sync_token {
         // If there is no cursor_token, then we are doing
         // a normal sync, so just return all records from sync_token
-        match crate::sync_tokens::token_to_max_id(&sync_token) {
-            Err(()) =>
-                return error_resp(Status::InternalServerError, vec!["Invalid sync_token".into()]),
-            Ok(id) => Some(id)
+        match sync_token_to_seq(&db, &failures, &u, "sync_token", &sync_token) {
+            Err(resp) => return resp,
+            Ok(seq) if seq > current_max_seq.unwrap_or(0) => {
+                // The client has seen a state newer than anything we have,
+                // e.g. because the server was restored from an older backup.
+                // Retrieving from here would silently return nothing forever,
+                // so tell the client to drop its sync_token and start over.
+                return reset_sync_resp();
+            },
+            Ok(seq) => Some(seq)
         }
     } else {
         None
@@ -256,7 +859,7 @@ fn items_sync(
 
     // First, retrieve what the client needs
     let result = item::SyncItem::items_of_user(&db.0, &u,
-        from_id, None, inner_params.limit);
+        from_seq, None, limit, updated_after.as_deref());
 
     match result {
         Err(item::ItemOpError(e)) => {
@@ -267,32 +870,73 @@ fn items_sync(
                 // If we fetched something, and the length is right at limit
                 // we may have more to fetch. In this case, we need to
                 // inform the client to continue fetching
-                let next_from = items.last().unwrap().id;
-                if let Some(limit) = inner_params.limit {
+                let next_from = items.last().unwrap().user_seq;
+                if let Some(limit) = limit {
                     if items.len() as i64 == limit {
                         // We may still have something to fetch
-                        resp.cursor_token = Some(crate::sync_tokens::max_id_to_token(next_from));
+                        resp.cursor_token = Some(crate::sync_tokens::seq_to_token(next_from));
                     }
                 }
             }
 
-            resp.retrieved_items = items.into_iter().map(|x| x.into()).collect();
+            if inner_params.return_remaining {
+                // Note that this is computed before we save anything
+                // from this request, just like cursor_token above
+                resp.remaining = match items.last() {
+                    None => Some(0),
+                    Some(last) => match item::SyncItem::count_items_after(&db.0, &u, last.user_seq, updated_after.as_deref()) {
+                        Ok(count) => Some(count),
+                        Err(item::ItemOpError(e)) =>
+                            return error_resp(Status::InternalServerError, vec![e])
+                    }
+                };
+            }
+
+            resp.retrieved_items = to_sync_items(&config, items);
         }
     }
 
     // Detect conflicts between client items and server items
-    let (items_conflicted, items_to_save): (Vec<_>, Vec<_>) =
-        inner_params.items.into_iter().partition_map(|client_item| {
+    // A resolution for an item supersedes any plain update to it
+    let resolutions = inner_params.resolve_conflicts;
+    let (items_conflicted, mut items_to_save): (Vec<_>, Vec<_>) =
+        inner_params.items.into_iter()
+        .filter(|client_item| !resolutions.iter().any(|x| x.uuid == client_item.uuid))
+        .partition_map(|client_item| {
             let conflict: Vec<_> = resp.retrieved_items.iter()
                 .filter(|server_item| client_item.uuid == server_item.uuid)
                 .collect();
             if !conflict.is_empty() {
                 Either::Left((client_item, conflict[0].clone()))
             } else {
+                #[cfg(feature = "testing")]
+                {
+                    if let Some(server_item) =
+                            crate::testing::forced_conflict(&db.0, &u, &client_item.uuid) {
+                        return Either::Left((client_item, server_item));
+                    }
+                }
                 Either::Right(client_item)
             }
         });
 
+    // Items changed too recently are not updated again yet,
+    // no matter which device changed them (deletions always go through)
+    let mut items_throttled = vec![];
+    if let Some(interval) = config.min_item_update_interval_secs {
+        let since = chrono::Utc::now() - chrono::Duration::seconds(interval);
+        let mut allowed = vec![];
+        for it in items_to_save.into_iter() {
+            match item::SyncItem::updated_since(&db.0, &u, &it.uuid, since) {
+                Ok(true) if !it.deleted => items_throttled.push(it),
+                Ok(_) => allowed.push(it),
+                Err(item::ItemOpError(e)) =>
+                    return error_resp(Status::InternalServerError, vec![e])
+            }
+        }
+        items_to_save = allowed;
+    }
+
     // Convert conflicts into the format our client wants
     resp.conflicts = items_conflicted.into_iter().map(|(_client_item, server_item)| {
         // Our implementation never produces `uuid_conflict`
@@ -306,27 +950,70 @@ fn items_sync(
             server_item: Some(server_item),
             unsaved_item: None
         }
-    }).collect();
+    }).chain(items_throttled.into_iter().map(|unsaved_item| {
+        // The client should simply try again later
+        SyncConflict {
+            conf_type: UPDATE_THROTTLED.to_string(),
+            server_item: None,
+            unsaved_item: Some(unsaved_item)
+        }
+    })).collect();
+    metrics.add_conflicts(resp.conflicts.len() as u64);
+
+    // Resolved items do not need to be sent back to the client
+    resp.retrieved_items.retain(|x| !resolutions.iter().any(|y| x.uuid == y.uuid));
+    items_to_save.extend(resolutions);
+
+    // Oversized items are skipped, while the rest of them are saved as usual
+    if let Some(max_size) = config.max_item_size_bytes {
+        let (oversized, rest): (Vec<_>, Vec<_>) = items_to_save.into_iter()
+            .partition(|it| it.content.as_ref().map(|c| c.len()).unwrap_or(0) > max_size);
+        resp.unsaved.extend(oversized.into_iter().map(|it| UnsavedItem {
+            uuid: it.uuid,
+            error: format!("Item content must not be larger than {} bytes", max_size)
+        }));
+        items_to_save = rest;
+    }
+
+    // Only deletions are accepted once the database has hit its size limit
+    if let Some(max_size) = config.max_db_size_bytes {
+        if items_to_save.iter().any(|it| !it.deleted) {
+            match crate::db::database_size(&db.0) {
+                Ok(size) if size >= max_size =>
+                    return error_resp(Status::InsufficientStorage,
+                        vec!["The server has run out of storage space".into()]),
+                Ok(_) => {},
+                Err(e) => return error_resp(Status::InternalServerError, vec![e])
+            }
+        }
+    }
 
     // Then, update all items sent by client
-    let mut last_id: i64 = -1;
+    let mut last_seq: i64 = -1;
     for mut it in items_to_save.into_iter() {
         // Always update updated_at for all items on server
         it.updated_at = 
             Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
 
-        match item::SyncItem::items_insert(&db.0, &u, &it) {
-            Err(item::ItemOpError(e)) => {
-                return error_resp(Status::InternalServerError, vec![e]);
+        match item::SyncItem::items_insert(&db.0, &u, &it, config.inline_content_max_bytes) {
+            // Do not throw away what has been saved so far
+            // (the sync_token below has to cover those items)
+            Err(item::ItemOpError(error)) => {
+                resp.unsaved.push(UnsavedItem {
+                    uuid: it.uuid,
+                    error
+                });
             },
-            Ok(id) => {
-                last_id = id;
-                resp.saved_items.push(it);
+            Ok(seq) => {
+                last_seq = seq;
+                if let Some(ref mut saved_items) = resp.saved_items {
+                    saved_items.push(it);
+                }
             }
         }
     }
 
-    if last_id > -1 {
+    if last_seq > -1 {
         // Since we have added more items to the database,
         // the sync_token we had no longer points to the latest item
         // Update sync_token to the latest one of our saved items
@@ -334,16 +1021,453 @@ fn items_sync(
         // LATEST known state of the system by the client,
         // but it MAY still need to fill in a bit of history
         // (that's where `cursor_token` comes into play)
-        resp.sync_token = Some(crate::sync_tokens::max_id_to_token(last_id));
+        resp.sync_token = Some(crate::sync_tokens::seq_to_token(last_seq));
     }
 
     // Remove conflicted items from retrieved items
     let conflicts = &resp.conflicts;
     resp.retrieved_items = resp.retrieved_items.into_iter().filter(|x| {
         !conflicts.iter()
-            .map(|y| x.uuid == y.uuid())
+            .map(|y| y.uuid() == Some(x.uuid.as_str()))
             .fold(false, |x, y| x || y)
     }).collect();
+    // Only now, as conflicts carry the whole server item for merging
+    if inner_params.metadata_only {
+        resp.retrieved_items = resp.retrieved_items.into_iter()
+            .map(|it| it.without_content())
+            .collect();
+    }
 
+    // Items may have been saved by now, and the client needs the sync_token
+    // covering them, so nothing below is worth failing the sync for
+    resp.notices = match Notice::unacknowledged(&db.0, &u) {
+        Ok(notices) => notices.into_iter().map(|n| n.into()).collect(),
+        Err(e) => {
+            eprintln!("Cannot load notices of user {}: {}", u.id, e);
+            vec![]
+        }
+    };
+
+    // Still under the per-user lock, so concurrent syncs cannot race here
+    if let Err(user::UserOpError(e)) = u.touch_last_sync(&db.0) {
+        eprintln!("Cannot update last sync of user {}: {}", u.id, e);
+    }
+
+    if let Some(hash) = payload_hash {
+        dedup.record(u.id, hash, &resp);
+    }
     success_resp(resp)
+}
+
+#[derive(FromForm)]
+struct SearchParams {
+    content_type: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    updated_after: Option<String>,
+    updated_before: Option<String>,
+    deleted: Option<bool>
+}
+
+#[derive(Serialize)]
+struct SearchResp {
+    items: Vec<item::SyncItem>
+}
+
+// Parse an optional timestamp in query parameters
+fn timestamp_param(name: &str, val: Option<String>) -> Result<Option<String>, String> {
+    match val {
+        None => Ok(None),
+        Some(val) => item::normalize_timestamp(&val)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid timestamp for {}", name))
+    }
+}
+
+impl SearchParams {
+    fn into_filter(self) -> Result<item::ItemFilter, String> {
+        Ok(item::ItemFilter {
+            content_type: self.content_type,
+            created_after: timestamp_param("created_after", self.created_after)?,
+            created_before: timestamp_param("created_before", self.created_before)?,
+            updated_after: timestamp_param("updated_after", self.updated_after)?,
+            updated_before: timestamp_param("updated_before", self.updated_before)?,
+            deleted: self.deleted,
+            ..Default::default()
+        })
+    }
+}
+
+// Search items by metadata (content is encrypted, so that's all we have)
+// (lenient, so that other query parameters like `pretty` are allowed)
+#[get("/items/search?<params..>")]
+fn items_search(
+    db: DbConn, config: State<Config>,
+    u: user::User, params: LenientForm<SearchParams>
+) -> Custom<JsonResp<SearchResp>> {
+    let filter = match params.into_inner().into_filter() {
+        Ok(filter) => filter,
+        Err(e) => return error_resp(Status::BadRequest, vec![e])
+    };
+
+    match item::SyncItem::search(&db.0, &u, &filter) {
+        Ok(items) => success_resp(SearchResp {
+            items: to_sync_items(&config, items)
+        }),
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Serialize)]
+struct ExportResp {
+    items: Vec<item::SyncItem>,
+    // Pass this back as `cursor` to fetch the next page
+    // None if this is the last page
+    cursor: Option<String>
+}
+
+// Export all (non-deleted) items of a user page by page, for backups
+// optionally only those of one content_type
+// The cursor is the user_seq of the last exported item, encrypted like sync_token.
+// An item updated during the export gets a new user_seq and will
+// show up again in a later page, so clients should dedupe by uuid.
+#[get("/items/export?<cursor>&<limit>&<content_type>")]
+fn items_export(
+    db: DbConn, config: State<Config>, u: user::User,
+    cursor: Option<String>, limit: Option<i64>, content_type: Option<String>
+) -> Custom<JsonResp<ExportResp>> {
+    let since_seq = match cursor {
+        None => None,
+        Some(cursor) => match token_to_seq(&db, &u, &cursor) {
+            Ok(Some(seq)) => Some(seq),
+            Ok(None) => return error_resp(Status::BadRequest, vec!["Invalid cursor".into()]),
+            Err(e) => return error_resp(Status::InternalServerError, vec![e])
+        }
+    };
+    let limit = clamp_sync_limit(limit).unwrap_or(DEFAULT_ITEMS_PER_SYNC);
+
+    let filter = item::ItemFilter {
+        content_type,
+        deleted: Some(false),
+        since_seq,
+        limit: Some(limit),
+        ..Default::default()
+    };
+    match item::SyncItem::search(&db.0, &u, &filter) {
+        Ok(items) => {
+            // A full page means there may be more to fetch
+            let cursor = match items.last() {
+                Some(last) if items.len() as i64 == limit =>
+                    Some(crate::sync_tokens::seq_to_token(last.user_seq)),
+                _ => None
+            };
+            success_resp(ExportResp {
+                items: to_sync_items(&config, items),
+                cursor
+            })
+        },
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+// Every items key of the user, regardless of any sync cursor,
+// so that a client can always recover what it needs for decryption
+#[get("/items/keys")]
+fn items_keys(db: DbConn, config: State<Config>, u: user::User) -> Custom<JsonResp<SearchResp>> {
+    let filter = item::ItemFilter {
+        content_type: Some(item::ITEMS_KEY_CONTENT_TYPE.into()),
+        deleted: Some(false),
+        ..Default::default()
+    };
+    match item::SyncItem::search(&db.0, &u, &filter) {
+        Ok(items) => success_resp(SearchResp {
+            items: to_sync_items(&config, items)
+        }),
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+// SQLite limits the number of parameters in one query,
+// so lists of uuids to check or purge cannot be arbitrarily long
+const MAX_UUIDS_PER_QUERY: usize = 500;
+
+#[derive(Deserialize)]
+struct ExistsParams {
+    uuids: Vec<String>
+}
+
+#[derive(Serialize)]
+struct ExistsItem {
+    uuid: String,
+    exists: bool,
+    deleted: bool
+}
+
+#[derive(Serialize)]
+struct ExistsResp {
+    items: Vec<ExistsItem>
+}
+
+// Which of the given uuids exist on the server, without any content
+// Results are in the same order as the request
+#[post("/items/exists", format = "json", data = "<params>")]
+fn items_exists(db: DbConn, u: user::User, params: Json<ExistsParams>) -> Custom<JsonResp<ExistsResp>> {
+    if params.uuids.len() > MAX_UUIDS_PER_QUERY {
+        return error_resp(Status::BadRequest,
+            vec![format!("At most {} uuids can be checked at once", MAX_UUIDS_PER_QUERY)]);
+    }
+
+    match item::SyncItem::find_existing_uuids(&db.0, &u, &params.uuids) {
+        Ok(existing) => {
+            let existing: std::collections::HashMap<_, _> = existing.into_iter().collect();
+            success_resp(ExistsResp {
+                items: params.into_inner().uuids.into_iter().map(|uuid| ExistsItem {
+                    exists: existing.contains_key(&uuid),
+                    deleted: existing.get(&uuid).cloned().unwrap_or(false),
+                    uuid
+                }).collect()
+            })
+        },
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct PurgeParams {
+    uuids: Vec<String>
+}
+
+// Remove deleted items for good, leaving not even their metadata behind
+// Devices that have not synced since the deletion will never learn about it,
+// so clients should only offer this for items deleted a while ago.
+// Either all of the items are purged or none of them.
+#[post("/items/purge", format = "json", data = "<params>")]
+fn items_purge(
+    db: DbConn, lock: State<UserLock>, dedup: State<SyncDedup>,
+    u: user::User, params: Json<PurgeParams>
+) -> Custom<JsonResp<()>> {
+    if params.uuids.len() > MAX_UUIDS_PER_QUERY {
+        return error_resp(Status::BadRequest,
+            vec![format!("At most {} uuids can be purged at once", MAX_UUIDS_PER_QUERY)]);
+    }
+
+    // A sync of the same user must not bring an item back in between
+    let mutex = lock.get_mutex(u.id);
+    let _lock = lock.lock(&mutex);
+
+    let existing: std::collections::HashMap<_, _> =
+        match item::SyncItem::find_existing_uuids(&db.0, &u, &params.uuids) {
+            Ok(existing) => existing.into_iter().collect(),
+            Err(item::ItemOpError(e)) =>
+                return error_resp(Status::InternalServerError, vec![e])
+        };
+    let errors = params.uuids.iter()
+        .filter_map(|uuid| match existing.get(uuid) {
+            None => Some(format!("Item {} does not exist", uuid)),
+            Some(false) => Some(format!("Item {} is not deleted", uuid)),
+            Some(true) => None
+        })
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return error_resp(Status::BadRequest, errors);
+    }
+
+    match item::SyncItem::purge_tombstones(&db.0, &u, &params.uuids) {
+        Ok(_) => {
+            dedup.forget(u.id);
+            Custom(Status::NoContent, PrettyJson(Response::Success(())))
+        },
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+// Stop receiving a server notice in sync responses
+#[post("/notices/<id>/ack")]
+fn notices_ack(db: DbConn, dedup: State<SyncDedup>, u: user::User, id: i32) -> Custom<JsonResp<()>> {
+    match Notice::acknowledge(&db.0, &u, id) {
+        Ok(true) => {
+            dedup.forget(u.id);
+            Custom(Status::NoContent, PrettyJson(Response::Success(())))
+        },
+        Ok(false) => error_resp(Status::NotFound, vec!["No matching notice found".into()]),
+        Err(e) => error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct SuspendParams {
+    email: String,
+    suspended: bool
+}
+
+// Suspend or reinstate an account without touching its data
+#[post("/admin/suspend", format = "json", data = "<params>")]
+fn admin_suspend(db: DbConn, _admin: user::Admin, params: Json<SuspendParams>) -> Custom<JsonResp<()>> {
+    match user::User::set_suspended(&db.0, &params.email, params.suspended) {
+        Ok(_) => Custom(Status::NoContent, PrettyJson(Response::Success(()))),
+        Err(user::UserOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct RestoreParams {
+    email: String
+}
+
+// Undo the deletion of an account that has not been purged yet
+#[post("/admin/restore", format = "json", data = "<params>")]
+fn admin_restore(db: DbConn, _admin: user::Admin, params: Json<RestoreParams>) -> Custom<JsonResp<()>> {
+    match user::User::restore(&db.0, &params.email) {
+        Ok(_) => Custom(Status::NoContent, PrettyJson(Response::Success(()))),
+        Err(user::UserOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Deserialize)]
+struct NoticeParams {
+    message: String
+}
+
+#[derive(Serialize)]
+struct NoticeCreated {
+    id: i32
+}
+
+// Show a notice to every user on their next syncs
+#[post("/admin/notices", format = "json", data = "<params>")]
+fn admin_notices(
+    db: DbConn, dedup: State<SyncDedup>, _admin: user::Admin, params: Json<NoticeParams>
+) -> Custom<JsonResp<NoticeCreated>> {
+    if params.message.is_empty() {
+        return error_resp(Status::BadRequest, vec!["Notice must not be empty".into()]);
+    }
+
+    match Notice::create(&db.0, &params.message) {
+        Ok(id) => {
+            dedup.forget_all();
+            success_resp(NoticeCreated { id })
+        },
+        Err(e) => error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Serialize)]
+struct AdminUser {
+    uuid: String,
+    email: String,
+    suspended: bool,
+    last_sync: Option<String>
+}
+
+#[derive(Serialize)]
+struct AdminUsersResp {
+    users: Vec<AdminUser>
+}
+
+#[get("/admin/users")]
+fn admin_users(db: DbConn, _admin: user::Admin) -> Custom<JsonResp<AdminUsersResp>> {
+    match user::User::all(&db.0) {
+        Ok(all) => success_resp(AdminUsersResp {
+            users: all.into_iter().map(|u| AdminUser {
+                uuid: u.uuid,
+                email: u.email,
+                suspended: u.suspended,
+                last_sync: u.last_sync.map(format_timestamp)
+            }).collect()
+        }),
+        Err(user::UserOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
+}
+
+#[derive(Serialize)]
+struct AdminLocksResp {
+    // Requests waiting to write to the database
+    db_write_waiters: usize,
+    // Per-user sync locks currently held (by syncs and background tasks)
+    user_locks_held: usize
+}
+
+// Lock contention right now, for debugging latency
+#[get("/admin/locks")]
+fn admin_locks(lock: State<UserLock>, _admin: user::Admin) -> Custom<JsonResp<AdminLocksResp>> {
+    success_resp(AdminLocksResp {
+        db_write_waiters: crate::DB_WRITE_WAITERS.load(std::sync::atomic::Ordering::SeqCst),
+        user_locks_held: lock.held()
+    })
+}
+
+#[derive(Serialize)]
+struct AdminItem {
+    uuid: String,
+    content_type: String,
+    deleted: bool,
+    created_at: String,
+    updated_at: Option<String>,
+    // Length of the encrypted content in bytes
+    size: usize
+}
+
+#[derive(Serialize)]
+struct AdminItemsResp {
+    items: Vec<AdminItem>,
+    // Same as in ExportResp
+    cursor: Option<String>
+}
+
+// Metadata of the items of a user, including deleted ones, page by page
+// Never shows content (which is encrypted anyway) or item keys
+#[get("/admin/users/<user_uuid>/items?<cursor>&<limit>")]
+fn admin_user_items(
+    db: DbConn, _admin: user::Admin, user_uuid: String,
+    cursor: Option<String>, limit: Option<i64>
+) -> Custom<JsonResp<AdminItemsResp>> {
+    let u = match user::User::find_user_by_uuid(&db.0, &user_uuid) {
+        Ok(u) => u,
+        Err(user::UserOpError(e)) => return error_resp(Status::NotFound, vec![e])
+    };
+    let since_seq = match cursor {
+        None => None,
+        Some(cursor) => match token_to_seq(&db, &u, &cursor) {
+            Ok(Some(seq)) => Some(seq),
+            Ok(None) => return error_resp(Status::BadRequest, vec!["Invalid cursor".into()]),
+            Err(e) => return error_resp(Status::InternalServerError, vec![e])
+        }
+    };
+    let limit = clamp_sync_limit(limit).unwrap_or(DEFAULT_ITEMS_PER_SYNC);
+
+    let filter = item::ItemFilter {
+        since_seq,
+        limit: Some(limit),
+        ..Default::default()
+    };
+    match item::SyncItem::search(&db.0, &u, &filter) {
+        Ok(items) => {
+            let cursor = match items.last() {
+                Some(last) if items.len() as i64 == limit =>
+                    Some(crate::sync_tokens::seq_to_token(last.user_seq)),
+                _ => None
+            };
+            success_resp(AdminItemsResp {
+                items: items.into_iter().map(|it| AdminItem {
+                    size: it.content.as_ref().map(|c| c.len()).unwrap_or(0),
+                    uuid: it.uuid,
+                    content_type: it.content_type,
+                    deleted: it.deleted,
+                    created_at: it.created_at,
+                    updated_at: it.updated_at
+                }).collect(),
+                cursor
+            })
+        },
+        Err(item::ItemOpError(e)) =>
+            error_resp(Status::InternalServerError, vec![e])
+    }
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Optional settings, read from the environment (or `.env`)
+// Everything in here has a default, so that the minimal
+// configuration described in README keeps working
+#[derive(Clone)]
+pub struct Config {
+    // Display name of this instance, shown in /version
+    pub server_name: String,
+    // Whether to also send the server name in an X-Server-Name header
+    pub server_name_header: bool,
+    // Turn items created more than this many days ago into tombstones
+    pub item_max_age_days: Option<i64>,
+    // Allow only one registration per IP within this many minutes
+    pub registration_cooldown_minutes: Option<u64>,
+    // Reverse proxies in front of us; we take their word on the client IP
+    pub trusted_proxies: Vec<IpAddr>,
+    // Refuse to store new content once the database is this large
+    pub max_db_size_bytes: Option<i64>,
+    // Send Access-Control-Allow-Credentials; needs explicit origins
+    pub cors_allow_credentials: bool,
+    // Origins allowed for CORS; empty means any origin
+    pub cors_allowed_origins: Vec<String>,
+    // Users allowed to use the /admin endpoints
+    pub admin_emails: Vec<String>,
+    // Rewrite item timestamps into UTC RFC3339 with millis on read
+    pub normalize_timestamps: bool,
+    // Revoke the oldest tokens of a user beyond this many
+    pub max_sessions_per_user: Option<i64>,
+    // How many more times to try connecting to the database
+    // at startup, and how long to wait before the first retry
+    pub db_connect_retries: u32,
+    pub db_connect_backoff_ms: u64,
+    // Longest email address accepted at registration
+    pub max_email_length: usize,
+    // Content of a note given to every new user
+    // (stored as-is, so it is NOT encrypted like the rest)
+    pub seed_welcome_item: Option<String>,
+    // Reject items timestamped more than this far in the future
+    pub max_clock_skew_secs: Option<i64>,
+    // Content types to return under a different name, old => new
+    pub content_type_aliases: HashMap<String, String>,
+    // Indent all JSON responses
+    pub pretty_json: bool,
+    // Refuse to update an item again within this many seconds
+    pub min_item_update_interval_secs: Option<i64>,
+    // Keep deleted accounts this long before purging them
+    pub account_retention_days: i64,
+    // Refuse to save items with content larger than this
+    pub max_item_size_bytes: Option<usize>,
+    // Answer 429 to users with this many requests in flight
+    pub max_concurrent_requests_per_user: Option<usize>,
+    // Store item content larger than this outside the items table
+    pub inline_content_max_bytes: Option<usize>,
+    // Answer identical syncs of a user within this many seconds
+    // with the response to the first one
+    pub sync_dedup_window_secs: Option<u64>,
+    // Refuse tokens issued more than this many days ago
+    pub token_lifetime_days: Option<i64>,
+    // Hand out a new token along with responses to requests
+    // made with one that expires within this many hours
+    pub token_refresh_window_hours: Option<i64>
+}
+
+// Parse an optional environment variable
+// An invalid value is a configuration error and we refuse to start
+pub fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse()
+            .unwrap_or_else(|_| panic!("Invalid value for {}: {}", name, v)))
+}
+
+// Parse an optional comma-separated list
+pub fn env_parse_list<T: FromStr>(name: &str) -> Vec<T> {
+    env::var(name).ok()
+        .map(|v| v.split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse()
+                .unwrap_or_else(|_| panic!("Invalid value for {}: {}", name, x)))
+            .collect())
+        .unwrap_or_default()
+}
+
+// Parse an optional comma-separated list of `key=value` pairs
+pub fn env_parse_map(name: &str) -> HashMap<String, String> {
+    env_parse_list::<String>(name).into_iter()
+        .map(|x| match x.splitn(2, '=').map(|y| y.trim()).collect::<Vec<_>>()[..] {
+            [k, v] if !k.is_empty() && !v.is_empty() => (k.to_string(), v.to_string()),
+            _ => panic!("Invalid value for {}: {}", name, x)
+        })
+        .collect()
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        Config {
+            server_name: env_parse("SERVER_NAME")
+                .unwrap_or_else(|| "sfrs".to_string()),
+            server_name_header: env_parse("SERVER_NAME_HEADER")
+                .unwrap_or(false),
+            item_max_age_days: env_parse("ITEM_MAX_AGE_DAYS")
+                .filter(|days: &i64| *days > 0),
+            registration_cooldown_minutes: env_parse("REGISTRATION_COOLDOWN_MINUTES")
+                .filter(|m: &u64| *m > 0),
+            trusted_proxies: env_parse_list("TRUSTED_PROXIES"),
+            max_db_size_bytes: env_parse("MAX_DB_SIZE_BYTES")
+                .filter(|size: &i64| *size > 0),
+            cors_allow_credentials: env_parse("CORS_ALLOW_CREDENTIALS")
+                .unwrap_or(false),
+            cors_allowed_origins: env_parse_list("CORS_ALLOWED_ORIGINS"),
+            admin_emails: env_parse_list("ADMIN_EMAILS"),
+            normalize_timestamps: env_parse("NORMALIZE_TIMESTAMPS")
+                .unwrap_or(false),
+            max_sessions_per_user: env_parse("MAX_SESSIONS_PER_USER")
+                .filter(|max: &i64| *max > 0),
+            db_connect_retries: env_parse("DB_CONNECT_RETRIES")
+                .unwrap_or(5),
+            db_connect_backoff_ms: env_parse("DB_CONNECT_BACKOFF_MS")
+                .unwrap_or(500),
+            // RFC 5321 limits a forward-path to 256 octets, i.e. 254 for the address
+            max_email_length: env_parse("MAX_EMAIL_LENGTH")
+                .filter(|len: &usize| *len > 0)
+                .unwrap_or(254),
+            seed_welcome_item: env_parse("SEED_WELCOME_ITEM"),
+            max_clock_skew_secs: match env_parse("MAX_CLOCK_SKEW_SECS") {
+                None => Some(3600),
+                Some(0) => None,
+                secs => secs
+            },
+            content_type_aliases: env_parse_map("CONTENT_TYPE_ALIASES"),
+            pretty_json: env_parse("PRETTY_JSON")
+                .unwrap_or(false),
+            min_item_update_interval_secs: env_parse("MIN_ITEM_UPDATE_INTERVAL")
+                .filter(|secs: &i64| *secs > 0),
+            account_retention_days: env_parse("ACCOUNT_RETENTION_DAYS")
+                .filter(|days: &i64| *days >= 0)
+                .unwrap_or(30),
+            max_item_size_bytes: env_parse("MAX_ITEM_SIZE_BYTES")
+                .filter(|size: &usize| *size > 0),
+            max_concurrent_requests_per_user: env_parse("MAX_CONCURRENT_REQUESTS_PER_USER")
+                .filter(|max: &usize| *max > 0),
+            inline_content_max_bytes: env_parse("INLINE_CONTENT_MAX_BYTES"),
+            sync_dedup_window_secs: env_parse("SYNC_DEDUP_WINDOW_SECS")
+                .filter(|secs: &u64| *secs > 0),
+            token_lifetime_days: env_parse("TOKEN_LIFETIME_DAYS")
+                .filter(|days: &i64| *days > 0),
+            token_refresh_window_hours: env_parse("TOKEN_REFRESH_WINDOW_HOURS")
+                .filter(|hours: &i64| *hours > 0)
+        }
+    }
+}
@@ -4,8 +4,11 @@ use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
 use diesel::result::{ConnectionResult, QueryResult};
 use diesel::sqlite::{Sqlite, SqliteConnection};
 use diesel::sql_types::*;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 use rocket_contrib::databases::{r2d2, DatabaseConfig, Poolable};
-use std::sync::RwLock;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 // We need a global RwLock for SQLite
 // This is unfortunate when we still use SQLite
@@ -18,24 +21,108 @@ lazy_static! {
     pub static ref DB_LOCK: RwLock<()> = RwLock::new(());
 }
 
+// How many threads are waiting to lock DB_LOCK for writing right now
+// (see /admin/locks)
+pub static DB_WRITE_WAITERS: AtomicUsize = AtomicUsize::new(0);
+
+// A panic while holding DB_LOCK (see api::catch_panics) poisons it, but
+// SQLite keeps the database consistent anyway (an unfinished transaction
+// is rolled back), so we go on instead of failing every request from then on
+pub fn lock_db_for_write() -> RwLockWriteGuard<'static, ()> {
+    DB_WRITE_WAITERS.fetch_add(1, Ordering::SeqCst);
+    let guard = DB_LOCK.write().unwrap_or_else(|e| e.into_inner());
+    DB_WRITE_WAITERS.fetch_sub(1, Ordering::SeqCst);
+    guard
+}
+
+pub fn lock_db_for_read() -> RwLockReadGuard<'static, ()> {
+    DB_LOCK.read().unwrap_or_else(|e| e.into_inner())
+}
+
+// These still return a Result, so that callers can treat them
+// like any other database operation
 #[macro_export]
 macro_rules! lock_db_write {
     () => {
-        crate::DB_LOCK.write()
-            .map_err(|_| "Cannot lock database for writing".into())
+        Ok(crate::lock_db_for_write())
     };
 }
 
 #[macro_export]
 macro_rules! lock_db_read {
     () => {
-        crate::DB_LOCK.read()
-            .map_err(|_| "Cannot lock database for reading".into())
+        Ok(crate::lock_db_for_read())
     };
 }
 
 pub trait SqliteLike = Connection<Backend = Sqlite>;
 
+#[derive(QueryableByName)]
+struct DatabaseSize {
+    #[sql_type = "BigInt"]
+    size: i64
+}
+
+// Size of the database in bytes, as SQLite sees it
+// (free pages are included; the file never shrinks without VACUUM)
+pub fn database_size(db: &impl SqliteLike) -> Result<i64, String> {
+    lock_db_read!()
+        .and_then(|_| {
+            diesel::sql_query("SELECT page_count * page_size AS size FROM pragma_page_count(), pragma_page_size()")
+                .get_result::<DatabaseSize>(db)
+                .map(|x| x.size)
+                .map_err(|_| "Database error".into())
+        })
+}
+
+// Whether the database can be read from
+pub fn check_read(db: &impl SqliteLike) -> Result<(), String> {
+    use crate::schema::health_check::dsl::*;
+    lock_db_read!()
+        .and_then(|_| {
+            health_check.select(id)
+                .load::<i32>(db)
+                .map(|_| ())
+                .map_err(|_| "Database error".into())
+        })
+}
+
+// Whether the database can be written to, by touching a scratch row
+// SQLite may stay readable when writes fail, e.g. when the disk is full
+pub fn check_write(db: &impl SqliteLike) -> Result<(), String> {
+    use crate::schema::health_check::dsl::*;
+    lock_db_write!()
+        .and_then(|_| {
+            diesel::replace_into(health_check)
+                .values((id.eq(1), checked_at.eq(diesel::dsl::now)))
+                .execute(db)
+                .map(|_| ())
+                .map_err(|_| "Database error".into())
+        })
+}
+
+// Call `f` until it succeeds, at most `retries` more times after the
+// first failure, doubling the delay in between every time
+pub fn retry_with_backoff<T, E: std::fmt::Display>(
+    retries: u32, backoff: Duration,
+    mut f: impl FnMut() -> Result<T, E>
+) -> Result<T, E> {
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt < retries => {
+                eprintln!("{}, retrying in {:?}", e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
 pub struct BusyWaitSqliteConnection(SqliteConnection);
 
 impl Poolable for BusyWaitSqliteConnection {
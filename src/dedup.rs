@@ -0,0 +1,73 @@
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Remembers the last sync of every user for a little while (see
+// SYNC_DEDUP_WINDOW_SECS), so that a client retrying a sync, e.g. after
+// a timeout, gets the same response again instead of saving it all twice
+// (clones share the same responses, so that background tasks can forget
+//  those they have made outdated)
+#[derive(Clone)]
+pub struct SyncDedup<T> {
+    // None if disabled
+    window: Option<Duration>,
+    last_syncs: Arc<Mutex<HashMap<i32, LastSync<T>>>>
+}
+
+struct LastSync<T> {
+    payload_hash: Vec<u8>,
+    at: Instant,
+    resp: T
+}
+
+impl<T: Clone> SyncDedup<T> {
+    pub fn new(window_secs: Option<u64>) -> SyncDedup<T> {
+        SyncDedup {
+            window: window_secs.map(Duration::from_secs),
+            last_syncs: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // What identifies a payload, None if disabled
+    pub fn payload_hash(&self, payload: &impl Serialize) -> Option<Vec<u8>> {
+        self.window?;
+        serde_json::to_vec(payload).ok()
+            .map(|json| digest(&SHA256, &json).as_ref().to_vec())
+    }
+
+    // The response to the last sync of the user,
+    // if it was made with the same payload within the window
+    pub fn lookup(&self, uid: i32, payload_hash: &[u8]) -> Option<T> {
+        let window = self.window?;
+        let last_syncs = self.last_syncs.lock().unwrap_or_else(|e| e.into_inner());
+        last_syncs.get(&uid)
+            .filter(|last| last.payload_hash == payload_hash && last.at.elapsed() < window)
+            .map(|last| last.resp.clone())
+    }
+
+    pub fn record(&self, uid: i32, payload_hash: Vec<u8>, resp: &T) {
+        let window = match self.window {
+            Some(window) => window,
+            None => return
+        };
+        let mut last_syncs = self.last_syncs.lock().unwrap_or_else(|e| e.into_inner());
+        // Responses can be large, so do not keep them around any longer than needed
+        last_syncs.retain(|_, last| last.at.elapsed() < window);
+        last_syncs.insert(uid, LastSync {
+            payload_hash,
+            at: Instant::now(),
+            resp: resp.clone()
+        });
+    }
+    // Whatever changes the response to a sync other than the sync itself
+    // has to make sure that a retry does not get the old one
+    pub fn forget(&self, uid: i32) {
+        self.last_syncs.lock().unwrap_or_else(|e| e.into_inner()).remove(&uid);
+    }
+
+    pub fn forget_all(&self) {
+        self.last_syncs.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
\ No newline at end of file
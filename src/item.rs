@@ -1,7 +1,8 @@
-use crate::schema::items;
+use crate::schema::{items, large_contents, users};
 use crate::schema::items::dsl::*;
 use crate::{SqliteLike, lock_db_write, lock_db_read};
 use crate::user;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use diesel::dsl::max;
 use diesel::prelude::*;
 use serde::{Serialize, Deserialize};
@@ -24,14 +25,12 @@ impl Into<ItemOpError> for &str {
 
 #[derive(Queryable)]
 pub struct Item {
-    // This "id", though primary key, is not how the client actually
-    // identifies an item, and it is not sent to the client.
-    // Instead, this "id" is more like a "timestamp", in the sense
-    // that each time an item is modified, it increments.
-    // (this incrementing is achieved by deleting and re-inserting
-    //  the item and relying on AUTOINCREMENT)
-    // This is used in place of the role of timestamp in the Ruby
-    // and Go implementation.
+    // Primary key, shared by the items of all users
+    // Not used for ordering anymore (see user_seq), because
+    // changes in it would leak what other users are doing
+    // (old sync_tokens carrying it are resolved by querying
+    //  the column directly, see seq_of_legacy_id)
+    #[allow(dead_code)]
     pub id: i64,
     pub owner: i32,
     pub uuid: String,
@@ -40,7 +39,17 @@ pub struct Item {
     pub enc_item_key: Option<String>,
     pub deleted: bool,
     pub created_at: String,
-    pub updated_at: Option<String>
+    pub updated_at: Option<String>,
+    pub app_metadata: Option<String>,
+    // This "user_seq" is not how the client actually identifies
+    // an item, and it is not sent to the client.
+    // Instead, it is more like a "timestamp", in the sense
+    // that each time an item of a user is modified, it increments.
+    // (this incrementing is achieved by deleting and re-inserting
+    //  the item with the next value of users.last_item_seq)
+    // This is used in place of the role of timestamp in the Ruby
+    // and Go implementation.
+    pub user_seq: i64
 }
 
 #[derive(Insertable)]
@@ -53,7 +62,9 @@ struct InsertItem {
     enc_item_key: Option<String>,
     deleted: bool,
     created_at: String,
-    updated_at: Option<String>
+    updated_at: Option<String>,
+    app_metadata: Option<String>,
+    user_seq: i64
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -65,7 +76,70 @@ pub struct SyncItem {
     #[serde(default)]
     pub deleted: bool,
     pub created_at: String,
-    pub updated_at: Option<String>
+    pub updated_at: Option<String>,
+    // Opaque to the server, e.g. a "protected" flag of some clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_metadata: Option<String>
+}
+
+// Content type of the keys used to encrypt other items (protocol 004)
+pub const ITEMS_KEY_CONTENT_TYPE: &str = "SN|ItemsKey";
+
+// Filters for searching through the items of a user
+// Content is encrypted, so we can only ever look at metadata.
+// Timestamps are compared as strings, so they should be passed
+// through normalize_timestamp first.
+#[derive(Default)]
+pub struct ItemFilter {
+    pub content_type: Option<String>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub updated_after: Option<String>,
+    pub updated_before: Option<String>,
+    pub deleted: Option<bool>,
+    // Only items with user_seq larger than this, for paging
+    pub since_seq: Option<i64>,
+    pub limit: Option<i64>
+}
+
+// Formats without a timezone, which we take as UTC
+// (the first one is what SQLite's CURRENT_TIMESTAMP looks like)
+const NAIVE_TIMESTAMP_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+// Convert a timestamp into the format we (and the clients) write,
+// e.g. `2020-02-22T11:07:35.000Z`, so that they can be compared as strings
+// Accepts RFC3339, RFC2822 and the naive formats above.
+pub fn normalize_timestamp(s: &str) -> Option<String> {
+    parse_timestamp(s)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+pub fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_rfc2822(s))
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+        .or_else(|| NAIVE_TIMESTAMP_FORMATS.iter()
+            .filter_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+            .next()
+            .map(|t| Utc.from_utc_datetime(&t)))
+}
+
+// Items along with their content stored in large_contents, if any
+// (see SyncItem::items_insert), to be loaded through with_large_content
+macro_rules! items_with_large_content {
+    () => {
+        items.left_join(large_contents::table.on(
+                large_contents::owner.eq(owner).and(large_contents::uuid.eq(uuid))))
+            .select((items::all_columns, large_contents::content.nullable()))
+    };
+}
+
+fn with_large_content((mut it, large): (Item, Option<String>)) -> Item {
+    if large.is_some() {
+        it.content = large;
+    }
+    it
 }
 
 impl Into<SyncItem> for Item {
@@ -77,96 +151,347 @@ impl Into<SyncItem> for Item {
             enc_item_key: self.enc_item_key,
             deleted: self.deleted,
             created_at: self.created_at,
-            updated_at: self.updated_at
+            updated_at: self.updated_at,
+            app_metadata: self.app_metadata
         }
     }
 }
 
 impl SyncItem {
+    // A new note with the given content, as a server-side client would create it
+    pub fn new_note(note_content: &str) -> SyncItem {
+        let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        SyncItem {
+            uuid: ::uuid::Uuid::new_v4().to_hyphenated().to_string(),
+            content: Some(note_content.into()),
+            content_type: "Note".into(),
+            enc_item_key: None,
+            deleted: false,
+            created_at: now.clone(),
+            updated_at: Some(now),
+            app_metadata: None
+        }
+    }
+
+    // Rewrite timestamps in our canonical format
+    // Anything we cannot make sense of is left alone
+    pub fn normalize_timestamps(mut self) -> SyncItem {
+        if let Some(t) = normalize_timestamp(&self.created_at) {
+            self.created_at = t;
+        }
+        self.updated_at = self.updated_at
+            .map(|t| normalize_timestamp(&t).unwrap_or(t));
+        self
+    }
+
+    // Whether any timestamp of this item is later than `latest`
+    // Timestamps we cannot parse are left for the client to deal with
+    pub fn is_from_future(&self, latest: DateTime<Utc>) -> bool {
+        std::iter::once(&self.created_at)
+            .chain(self.updated_at.iter())
+            .filter_map(|t| parse_timestamp(t))
+            .any(|t| t > latest)
+    }
+
+    pub fn without_content(mut self) -> SyncItem {
+        self.content = None;
+        self.enc_item_key = None;
+        self
+    }
+
     pub fn items_of_user(
         db: &impl SqliteLike, u: &user::User,
-        since_id: Option<i64>, max_id: Option<i64>,
-        limit: Option<i64>
+        since_seq: Option<i64>, max_seq: Option<i64>,
+        limit: Option<i64>, updated_after: Option<&str>
     ) -> Result<Vec<Item>, ItemOpError> {
         lock_db_read!()
             .and_then(|_| {
-                let mut stmt = items.filter(owner.eq(u.id)).into_boxed();
+                let mut stmt = items_with_large_content!()
+                    .filter(owner.eq(u.id))
+                    .into_boxed();
                 if let Some(limit) = limit {
                     stmt = stmt.limit(limit);
                 }
 
-                if let Some(since_id) = since_id {
-                    stmt = stmt.filter(id.gt(since_id));
+                // Expects a normalized timestamp (see normalize_timestamp)
+                if let Some(t) = updated_after {
+                    stmt = stmt.filter(updated_at.gt(t));
                 }
 
-                if let Some(max_id) = max_id {
-                    stmt = stmt.filter(id.le(max_id));
+                if let Some(since_seq) = since_seq {
+                    stmt = stmt.filter(user_seq.gt(since_seq));
                 }
 
-                stmt.order(id.asc())
-                    .load::<Item>(db)
+                if let Some(max_seq) = max_seq {
+                    stmt = stmt.filter(user_seq.le(max_seq));
+                }
+
+                stmt.order(user_seq.asc())
+                    .load::<(Item, Option<String>)>(db)
+                    .map(|res| res.into_iter().map(with_large_content).collect())
+                    .map_err(|_| "Database error".into())
+            })
+    }
+
+    pub fn search(db: &impl SqliteLike, u: &user::User, filter: &ItemFilter) -> Result<Vec<Item>, ItemOpError> {
+        lock_db_read!()
+            .and_then(|_| {
+                let mut stmt = items_with_large_content!()
+                    .filter(owner.eq(u.id))
+                    .into_boxed();
+                if let Some(ref t) = filter.content_type {
+                    stmt = stmt.filter(content_type.eq(t));
+                }
+
+                if let Some(ref t) = filter.created_after {
+                    stmt = stmt.filter(created_at.gt(t));
+                }
+
+                if let Some(ref t) = filter.created_before {
+                    stmt = stmt.filter(created_at.lt(t));
+                }
+
+                if let Some(ref t) = filter.updated_after {
+                    stmt = stmt.filter(updated_at.gt(t));
+                }
+
+                if let Some(ref t) = filter.updated_before {
+                    stmt = stmt.filter(updated_at.lt(t));
+                }
+
+                if let Some(d) = filter.deleted {
+                    stmt = stmt.filter(deleted.eq(d));
+                }
+
+                if let Some(since_seq) = filter.since_seq {
+                    stmt = stmt.filter(user_seq.gt(since_seq));
+                }
+
+                if let Some(limit) = filter.limit {
+                    stmt = stmt.limit(limit);
+                }
+
+                stmt.order(user_seq.asc())
+                    .load::<(Item, Option<String>)>(db)
+                    .map(|res| res.into_iter().map(with_large_content).collect())
+                    .map_err(|_| "Database error".into())
+            })
+    }
+
+    // Count the items of a user with user_seq larger than `since_seq`,
+    // i.e. what is still left to fetch after a page ending at `since_seq`
+    pub fn count_items_after(
+        db: &impl SqliteLike, u: &user::User, since_seq: i64,
+        updated_after: Option<&str>
+    ) -> Result<i64, ItemOpError> {
+        lock_db_read!()
+            .and_then(|_| {
+                let mut stmt = items.filter(owner.eq(u.id).and(user_seq.gt(since_seq))).into_boxed();
+                if let Some(t) = updated_after {
+                    stmt = stmt.filter(updated_at.gt(t));
+                }
+                stmt.count()
+                    .get_result::<i64>(db)
                     .map_err(|_| "Database error".into())
             })
     }
 
     pub fn find_item_by_uuid(db: &impl SqliteLike, u: &user::User, i: &str) -> Result<Item, ItemOpError> {
         lock_db_read!()
+            .and_then(|_| {
+                items_with_large_content!()
+                    .filter(owner.eq(u.id).and(uuid.eq(i)))
+                    .first::<(Item, Option<String>)>(db)
+                    .map(with_large_content)
+                    .map_err(|_| "Database error".into())
+            })
+    }
+
+    // Whether the stored item `i` was last updated after `since`
+    // (false if there is no such item)
+    pub fn updated_since(
+        db: &impl SqliteLike, u: &user::User, i: &str, since: DateTime<Utc>
+    ) -> Result<bool, ItemOpError> {
+        let last_update = lock_db_read!()
             .and_then(|_| {
                 items.filter(owner.eq(u.id).and(uuid.eq(i)))
-                    .first::<Item>(db)
+                    .select(updated_at)
+                    .first::<Option<String>>(db)
+                    .optional()
+                    .map_err(|_| "Database error".into())
+            })?;
+        Ok(last_update
+            .and_then(|t| t)
+            .and_then(|t| parse_timestamp(&t))
+            .map(|t| t > since)
+            .unwrap_or(false))
+    }
+
+    // Look up which of the given uuids exist for a user,
+    // returning (uuid, deleted) for each of them that does
+    pub fn find_existing_uuids(
+        db: &impl SqliteLike, u: &user::User, uuids: &[String]
+    ) -> Result<Vec<(String, bool)>, ItemOpError> {
+        lock_db_read!()
+            .and_then(|_| {
+                items.filter(owner.eq(u.id).and(uuid.eq_any(uuids)))
+                    .select((uuid, deleted))
+                    .load::<(String, bool)>(db)
+                    .map_err(|_| "Database error".into())
+            })
+    }
+
+    // Get the current maximum user_seq of the items of a user.
+    // Remember that it does not identify items; instead, items are moved to the next
+    // user_seq every time they are updated (see Self::items_insert).
+    // The value returned by this function is more like a "timestamp" of the latest "state"
+    // It is read from the counter in users rather than the items themselves,
+    // so that it never goes back, even when the latest items are purged.
+    // None if the user never had any item.
+    pub fn get_current_max_seq(db: &impl SqliteLike, u: &user::User) -> Result<Option<i64>, ItemOpError> {
+        lock_db_read!()
+            .and_then(|_| {
+                users::table.find(u.id)
+                    .select(users::last_item_seq)
+                    .first::<i64>(db)
+                    .map(|seq| Some(seq).filter(|seq| *seq > 0))
                     .map_err(|_| "Database error".into())
             })
     }
 
-    // Get the current maximum item ID for a user.
-    // Remember that IDs do not identify item; instead, they are incremented to the largest value
-    // every time an item is updated (see Self::items_insert).
-    // The ID returned by this function is more like a "timestamp" of the latest "state"
-    pub fn get_current_max_id(db: &impl SqliteLike, u: &user::User) -> Result<Option<i64>, ItemOpError> {
+    // The user_seq a client had seen when it was given the global item ID `max_id`
+    // (i.e. a sync_token from before user_seq existed), which is that of the
+    // latest item saved before it. Both grow in the order items are saved.
+    // None if `max_id` is newer than any item of the user, just like a
+    // sync_token ahead of get_current_max_seq.
+    pub fn seq_of_legacy_id(db: &impl SqliteLike, u: &user::User, max_id: i64) -> Result<Option<i64>, ItemOpError> {
         lock_db_read!()
             .and_then(|_| {
-                items.filter(owner.eq(u.id))
+                let latest_id = items.filter(owner.eq(u.id))
                     .select(max(id))
                     .first::<Option<i64>>(db)
+                    .map_err(|_| "Database error".into())?;
+                if max_id > latest_id.unwrap_or(0) {
+                    return Ok(None);
+                }
+
+                items.filter(owner.eq(u.id).and(id.le(max_id)))
+                    .select(max(user_seq))
+                    .first::<Option<i64>>(db)
+                    .map(|seq| Some(seq.unwrap_or(0)))
                     .map_err(|_| "Database error".into())
             })
     }
 
-    pub fn items_insert(db: &impl SqliteLike, u: &user::User, it: &SyncItem) -> Result<i64, ItemOpError> {
-        // First, try to find the original item, if any, delete it, and insert a new one with the same UUID
-        // This way, the ID is updated each time an item is updated
+    // Content larger than `inline_content_max_bytes` is stored in large_contents
+    // instead of the items row, keeping the rows (and thus scans of them) small
+    pub fn items_insert(
+        db: &impl SqliteLike, u: &user::User, it: &SyncItem,
+        inline_content_max_bytes: Option<usize>
+    ) -> Result<i64, ItemOpError> {
+        // Delete the original item, if any, and insert a new one with the same UUID,
+        // all in one transaction
+        // This way, the user_seq is updated each time an item is updated
         // This method acts both as insertion and update
-        let orig = lock_db_read!()
+        let large_content = match (&it.content, inline_content_max_bytes) {
+            (Some(c), Some(max_size)) if !it.deleted && c.len() > max_size => Some(c.clone()),
+            _ => None
+        };
+        lock_db_write!()
+            .and_then(|_| db.transaction::<_, diesel::result::Error, _>(|| {
+                // The counter lives in users rather than being the largest user_seq of
+                // the items, so that a number is never handed out twice, even when
+                // the latest item is the one being replaced here
+                diesel::update(users::table.find(u.id))
+                    .set(users::last_item_seq.eq(users::last_item_seq + 1))
+                    .execute(db)?;
+                let seq = users::table.find(u.id)
+                    .select(users::last_item_seq)
+                    .first::<i64>(db)?;
+                diesel::delete(items.filter(uuid.eq(&it.uuid).and(owner.eq(u.id))))
+                    .execute(db)?;
+                // The item may have been stored either way before
+                // (or a previous save may have left the content behind)
+                diesel::delete(large_contents::table.filter(
+                        large_contents::owner.eq(u.id).and(large_contents::uuid.eq(&it.uuid))))
+                    .execute(db)?;
+
+                if let Some(ref c) = large_content {
+                    diesel::insert_into(large_contents::table)
+                        .values((
+                            large_contents::owner.eq(u.id),
+                            large_contents::uuid.eq(&it.uuid),
+                            large_contents::content.eq(c)
+                        ))
+                        .execute(db)?;
+                }
+
+                diesel::insert_into(items::table)
+                    .values(InsertItem {
+                        owner: u.id,
+                        uuid: it.uuid.clone(),
+                        content: if it.deleted || large_content.is_some() { None } else { it.content.clone() },
+                        content_type: it.content_type.clone(),
+                        enc_item_key: if it.deleted { None } else { it.enc_item_key.clone() },
+                        deleted: it.deleted,
+                        created_at: it.created_at.clone(),
+                        updated_at: it.updated_at.clone(),
+                        app_metadata: if it.deleted { None } else { it.app_metadata.clone() },
+                        user_seq: seq
+                    })
+                    .execute(db)
+                    .map(|_| ())
+            }).map_err(|_| "Database error".into()))?;
+
+        Self::find_item_by_uuid(db, u, &it.uuid)
+            .map(|i| i.user_seq)
+    }
+
+    // Remove tombstones of a user for good
+    // Items that are not deleted are left alone.
+    // Tombstones never have content, thus nothing in large_contents either.
+    pub fn purge_tombstones(db: &impl SqliteLike, u: &user::User, uuids: &[String]) -> Result<usize, ItemOpError> {
+        lock_db_write!()
             .and_then(|_| {
-                items.filter(uuid.eq(&it.uuid).and(owner.eq(u.id)))
-                    .load::<Item>(db)
+                diesel::delete(items.filter(owner.eq(u.id).and(deleted.eq(true)).and(uuid.eq_any(uuids))))
+                    .execute(db)
                     .map_err(|_| "Database error".into())
-            })?;
+            })
+    }
 
-        let _lock = lock_db_write!()?;
-        if !orig.is_empty() {
-            diesel::delete(items.filter(uuid.eq(&it.uuid).and(owner.eq(u.id))))
-                .execute(db)
-                .map(|_| ())
-                .map_err(|_| "Database error".into())?;
-        }
+    // Turn all items of a user created before `cutoff` into tombstones
+    // This goes through items_insert, so the tombstones get new user_seqs
+    // and every client picks up the deletion on its next sync.
+    // Items whose created_at we cannot parse are left alone.
+    pub fn expire_items_of_user(
+        db: &impl SqliteLike, u: &user::User, cutoff: DateTime<Utc>
+    ) -> Result<usize, ItemOpError> {
+        let expired: Vec<Item> = lock_db_read!()
+            .and_then(|_| {
+                items.filter(owner.eq(u.id).and(deleted.eq(false)))
+                    .load::<Item>(db)
+                    .map_err(|_| "Database error".into())
+            })?
+            .into_iter()
+            .filter(|it| DateTime::parse_from_rfc3339(&it.created_at)
+                .map(|t| t < cutoff)
+                .unwrap_or(false))
+            .collect();
 
-        diesel::insert_into(items::table)
-            .values(InsertItem {
-                owner: u.id,
+        let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        for it in expired.iter() {
+            Self::items_insert(db, u, &SyncItem {
                 uuid: it.uuid.clone(),
-                content: if it.deleted { None } else { it.content.clone() },
+                content: None,
                 content_type: it.content_type.clone(),
-                enc_item_key: if it.deleted { None } else { it.enc_item_key.clone() },
-                deleted: it.deleted,
+                enc_item_key: None,
+                deleted: true,
                 created_at: it.created_at.clone(),
-                updated_at: it.updated_at.clone()
-            })
-            .execute(db)
-            .map_err(|_| "Database error".into())?;
-        std::mem::drop(_lock);
+                updated_at: Some(now.clone()),
+                app_metadata: None
+            }, None)?;
+        }
 
-        Self::find_item_by_uuid(db, u, &it.uuid)
-            .map(|i| i.id)
+        Ok(expired.len())
     }
 }
\ No newline at end of file
@@ -1,23 +1,63 @@
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock, Mutex};
+use std::sync::{Arc, RwLock, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // A per-user lock used for sync requests
+// (clones share the same underlying locks, so that
+//  background tasks can serialize with sync requests)
+#[derive(Clone)]
 pub struct UserLock {
-    lock_map: RwLock<HashMap<i32, Arc<Mutex<()>>>>
+    lock_map: Arc<RwLock<HashMap<i32, Arc<Mutex<()>>>>>,
+    // How many of the mutexes are locked right now (see /admin/locks)
+    held: Arc<AtomicUsize>
+}
+
+// A locked per-user mutex, counted in UserLock::held
+pub struct HeldUserLock<'a> {
+    _guard: MutexGuard<'a, ()>,
+    held: Arc<AtomicUsize>
+}
+
+impl Drop for HeldUserLock<'_> {
+    fn drop(&mut self) {
+        self.held.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl UserLock {
     pub fn new() -> UserLock {
         UserLock {
-            lock_map: RwLock::new(HashMap::new())
+            lock_map: Arc::new(RwLock::new(HashMap::new())),
+            held: Arc::new(AtomicUsize::new(0))
         }
     }
 
     pub fn get_mutex(&self, uid: i32) -> Arc<Mutex<()>> {
-        if !self.lock_map.read().unwrap().contains_key(&uid) {
-            self.lock_map.write().unwrap().insert(uid, Arc::new(Mutex::new(())));
+        // Nothing can be left half-updated in the map by a panic,
+        // so poisoning is safe to ignore here
+        if let Some(mutex) = self.lock_map.read().unwrap_or_else(|e| e.into_inner()).get(&uid) {
+            return mutex.clone();
         }
 
-        self.lock_map.read().unwrap().get(&uid).unwrap().clone()
+        self.lock_map.write().unwrap_or_else(|e| e.into_inner())
+            .entry(uid)
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    // Lock a mutex returned by get_mutex
+    // The mutexes guard no data, so one held by a thread that panicked
+    // (see CatchPanic) must not lock its user out for good
+    pub fn lock<'a>(&self, mutex: &'a Mutex<()>) -> HeldUserLock<'a> {
+        let guard = mutex.lock().unwrap_or_else(|e| e.into_inner());
+        self.held.fetch_add(1, Ordering::SeqCst);
+        HeldUserLock {
+            _guard: guard,
+            held: self.held.clone()
+        }
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::SeqCst)
     }
 }
\ No newline at end of file
@@ -21,6 +21,14 @@ mod tokens;
 mod user;
 mod item;
 mod lock;
+mod config;
+mod tasks;
+mod ratelimit;
+mod metrics;
+mod notice;
+mod dedup;
+#[cfg(feature = "testing")]
+mod testing;
 
 #[cfg(test)]
 mod tests;
@@ -31,19 +39,16 @@ use diesel::prelude::*;
 use dotenv::dotenv;
 use rocket::Rocket;
 use rocket::config::{Config, Environment, Value, Limits};
+use rocket::fairing::AdHoc;
 use std::collections::HashMap;
 use std::env;
+use std::time::Duration;
 
 embed_migrations!();
 
 #[database("db")]
 pub struct DbConn(BusyWaitSqliteConnection);
 
-#[get("/")]
-fn index() -> &'static str {
-    "Hello, world!"
-}
-
 fn db_path() -> String {
     env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set")
@@ -77,34 +82,110 @@ fn build_config() -> Config {
         .unwrap()
 }
 
-fn run_db_migrations(rocket: Rocket) -> Rocket {
-    let db = DbConn::get_one(&rocket).expect("Could not connect to Database");
-    match embedded_migrations::run(&*db) {
-        Ok(()) => rocket,
-        Err(e) => {
-            // We should not do anything if database failed to migrate
-            panic!("Failed to run database migrations: {:?}", e);
-        }
-    }
+// The database may not be available right away, e.g. when its volume
+// is still being mounted, so we retry a few times before giving up
+// This has to happen before DbConn::fairing() builds the connection pool,
+// which fails for good if the database cannot be opened at that point
+fn run_db_migrations(url: &str, retries: u32, backoff: Duration) -> Result<(), String> {
+    retry_with_backoff(retries, backoff, || {
+        let db = BusyWaitSqliteConnection::establish(url)
+            .map_err(|e| format!("Could not connect to Database: {}", e))?;
+        embedded_migrations::run(&db)
+            .map_err(|e| format!("Failed to run database migrations: {:?}", e))
+    })
 }
 
 pub fn build_rocket() -> Rocket {
-    // Make CORS options
-    let cors = rocket_cors::CorsOptions {
-        allowed_origins: rocket_cors::AllowedOrigins::All,
+    build_rocket_with_config(config::Config::from_env())
+}
+
+// Make CORS options
+// Credentialed requests cannot be used with a wildcard origin
+// (per the CORS spec), so they require a list of explicit origins
+pub fn build_cors(config: &config::Config) -> Result<rocket_cors::Cors, String> {
+    let origins = &config.cors_allowed_origins;
+    let wildcard = origins.is_empty() || origins.iter().any(|o| o == "*");
+    if config.cors_allow_credentials && wildcard {
+        return Err("CORS_ALLOW_CREDENTIALS requires explicit CORS_ALLOWED_ORIGINS".into());
+    }
+
+    let (allowed_origins, send_wildcard) = if wildcard {
+        (rocket_cors::AllowedOrigins::All, true)
+    } else {
+        (rocket_cors::AllowedOrigins::some_exact(origins), false)
+    };
+
+    rocket_cors::CorsOptions {
+        allowed_origins,
         allowed_methods: vec![rocket::http::Method::Get, rocket::http::Method::Post]
             .into_iter().map(From::from).collect(),
         allowed_headers: rocket_cors::AllowedHeaders::all(),
-        send_wildcard: true,
+        // Browsers hide other headers from scripts
+        expose_headers: ["X-Refreshed-Token"].iter().map(|h| h.to_string()).collect(),
+        allow_credentials: config.cors_allow_credentials,
+        send_wildcard,
         ..Default::default()
-    }.to_cors().unwrap();
+    }.to_cors().map_err(|e| e.to_string())
+}
+
+pub fn build_rocket_with_config(config: config::Config) -> Rocket {
+    let cors = build_cors(&config)
+        .unwrap_or_else(|e| panic!("Invalid CORS configuration: {}", e));
+
+    sync_tokens::init();
+    let server_name = if config.server_name_header {
+        Some(config.server_name.clone())
+    } else {
+        None
+    };
+    let registration_limiter = ratelimit::RegistrationLimiter::new(
+        config.registration_cooldown_minutes, config.trusted_proxies.clone());
+    let user_concurrency = ratelimit::UserConcurrency::new(
+        config.max_concurrent_requests_per_user);
+    let sync_dedup = api::SyncDedup::new(config.sync_dedup_window_secs);
+    let db_retries = config.db_connect_retries;
+    let db_backoff = Duration::from_millis(config.db_connect_backoff_ms);
+
+    // We should not do anything if database failed to migrate
+    if let Err(e) = run_db_migrations(&db_path(), db_retries, db_backoff) {
+        panic!("{}", e);
+    }
 
     let r = rocket::custom(build_config())
         .attach(cors)
+        .attach(AdHoc::on_response("Server Name", move |_, resp| {
+            if let Some(ref name) = server_name {
+                resp.set_raw_header("X-Server-Name", name.clone());
+            }
+        }))
+        .attach(AdHoc::on_response("Refreshed Token", |req, resp| {
+            // Only once the request went through; see User::refresh_expiring_token
+            if resp.status().class().is_success() {
+                if let user::RefreshedToken(Some(ref token)) = *req.local_cache(|| user::RefreshedToken(None)) {
+                    resp.set_raw_header("X-Refreshed-Token", token.clone());
+                }
+            }
+        }))
         .attach(DbConn::fairing())
+        .attach(AdHoc::on_launch("Background Tasks", |rocket| {
+            tasks::spawn(
+                rocket.state::<config::Config>().unwrap().clone(),
+                rocket.state::<lock::UserLock>().unwrap().clone(),
+                rocket.state::<api::SyncDedup>().unwrap().clone());
+        }))
+        .manage(config)
         .manage(lock::UserLock::new())
-        .mount("/", api::routes());
-    run_db_migrations(r)
+        .manage(registration_limiter)
+        .manage(user_concurrency)
+        .manage(sync_dedup)
+        .manage(tokens::RefreshedTokens::new())
+        .manage(metrics::Metrics::new())
+        .manage(sync_tokens::DecryptionFailures::new())
+        .mount("/", api::catch_panics(api::routes()))
+        .register(api::catchers());
+    #[cfg(feature = "testing")]
+    let r = r.mount("/", api::catch_panics(testing::routes()));
+    r
 }
 
 fn main() {
@@ -0,0 +1,37 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters for operators, exported at /metrics
+// in the Prometheus text format
+pub struct Metrics {
+    // Conflicts reported to clients by /items/sync
+    conflicts: AtomicU64
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            conflicts: AtomicU64::new(0)
+        }
+    }
+
+    pub fn add_conflicts(&self, n: u64) {
+        self.conflicts.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, val: u64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, val).unwrap();
+        };
+        counter("sfrs_sync_conflicts_total",
+            "Number of conflicts reported to clients during sync", self.conflicts());
+        out
+    }
+}
@@ -0,0 +1,65 @@
+use crate::schema::{notice_acks, server_notices};
+use crate::{SqliteLike, lock_db_write, lock_db_read};
+use crate::user;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+// A message from the operators to every user, e.g. about maintenance
+// It is sent along with every sync until the user acknowledges it
+#[derive(Queryable)]
+pub struct Notice {
+    pub id: i32,
+    pub message: String,
+    pub created_at: NaiveDateTime
+}
+
+impl Notice {
+    pub fn create(db: &impl SqliteLike, message: &str) -> Result<i32, String> {
+        lock_db_write!()
+            .and_then(|_| db.transaction::<_, diesel::result::Error, _>(|| {
+                diesel::insert_into(server_notices::table)
+                    .values((
+                        server_notices::message.eq(message),
+                        server_notices::created_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(db)?;
+                server_notices::table.select(server_notices::id)
+                    .order(server_notices::id.desc())
+                    .first(db)
+            }).map_err(|_| "Database error".into()))
+    }
+
+    // Notices the user has not acknowledged yet, oldest first
+    pub fn unacknowledged(db: &impl SqliteLike, u: &user::User) -> Result<Vec<Notice>, String> {
+        lock_db_read!()
+            .and_then(|_| {
+                let acked = notice_acks::table.filter(notice_acks::user_id.eq(u.id))
+                    .select(notice_acks::notice_id);
+                server_notices::table.filter(server_notices::id.ne_all(acked))
+                    .order(server_notices::id.asc())
+                    .load::<Notice>(db)
+                    .map_err(|_| "Database error".into())
+            })
+    }
+
+    // Stop sending the notice to the user
+    // Returns false if there is no such notice
+    pub fn acknowledge(db: &impl SqliteLike, u: &user::User, nid: i32) -> Result<bool, String> {
+        let exists: Option<i32> = lock_db_read!()
+            .and_then(|_| server_notices::table.find(nid)
+                .select(server_notices::id)
+                .first(db)
+                .optional()
+                .map_err(|_| "Database error".to_string()))?;
+        if exists.is_none() {
+            return Ok(false);
+        }
+
+        lock_db_write!()
+            .and_then(|_| diesel::replace_into(notice_acks::table)
+                .values((notice_acks::notice_id.eq(nid), notice_acks::user_id.eq(u.id)))
+                .execute(db)
+                .map(|_| true)
+                .map_err(|_| "Database error".into()))
+    }
+}
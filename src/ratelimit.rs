@@ -0,0 +1,180 @@
+use crate::config::Config;
+use rocket::State;
+use rocket::request;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Allows one event per key within a time window
+// Keys are only remembered for as long as the window lasts,
+// so this does not grow unbounded.
+pub struct RateLimiter<K: Hash + Eq> {
+    window: Duration,
+    last_seen: Mutex<HashMap<K, Instant>>
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(window: Duration) -> RateLimiter<K> {
+        RateLimiter {
+            window,
+            last_seen: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Record an event for `key`, unless one has been recorded within the window
+    // Checking and recording happen under the same lock, so that concurrent
+    // events cannot all get through. Returns whether it was recorded.
+    pub fn try_record(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let window = self.window;
+        last_seen.retain(|_, t| now.duration_since(*t) < window);
+        if last_seen.contains_key(&key) {
+            return false;
+        }
+
+        last_seen.insert(key, now);
+        true
+    }
+
+    // Take back the event recorded for `key`, e.g. when it failed after all
+    pub fn forget(&self, key: &K) {
+        self.last_seen.lock().unwrap().remove(key);
+    }
+}
+
+// Limits registrations per client IP (see REGISTRATION_COOLDOWN_MINUTES)
+pub struct RegistrationLimiter {
+    // None if the cooldown is disabled
+    pub limiter: Option<RateLimiter<IpAddr>>,
+    // Addresses of trusted reverse proxies, which are never limited
+    // because every request behind them would share the same IP
+    pub exempt: Vec<IpAddr>
+}
+
+impl RegistrationLimiter {
+    pub fn new(cooldown_minutes: Option<u64>, exempt: Vec<IpAddr>) -> RegistrationLimiter {
+        RegistrationLimiter {
+            limiter: cooldown_minutes.map(|m| RateLimiter::new(Duration::from_secs(m * 60))),
+            exempt
+        }
+    }
+
+    // Returns the limiter to use for `ip`, if any
+    fn limiter_for(&self, ip: &ClientIp) -> Option<(&RateLimiter<IpAddr>, IpAddr)> {
+        match (&self.limiter, ip.0) {
+            (Some(limiter), Some(ip)) if !self.exempt.contains(&ip) => Some((limiter, ip)),
+            _ => None
+        }
+    }
+
+    // Whether a registration from `ip` may go ahead, counting it if so
+    pub fn try_record(&self, ip: &ClientIp) -> bool {
+        self.limiter_for(ip)
+            .map(|(limiter, ip)| limiter.try_record(ip))
+            .unwrap_or(true)
+    }
+
+    // For registrations that failed after all
+    pub fn forget(&self, ip: &ClientIp) {
+        if let Some((limiter, ip)) = self.limiter_for(ip) {
+            limiter.forget(&ip);
+        }
+    }
+}
+
+// Limits how many requests of the same user are handled at once
+// (see MAX_CONCURRENT_REQUESTS_PER_USER), taken by the User guard
+pub struct UserConcurrency {
+    // None if unlimited
+    max: Option<usize>,
+    in_flight: Arc<Mutex<HashMap<i32, usize>>>
+}
+
+// Held for as long as a request is being handled
+pub struct ConcurrencyPermit {
+    uid: i32,
+    in_flight: Arc<Mutex<HashMap<i32, usize>>>
+}
+
+impl UserConcurrency {
+    pub fn new(max: Option<usize>) -> UserConcurrency {
+        UserConcurrency {
+            max,
+            in_flight: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // None if the user already has too many requests in flight
+    // The permit has to be kept until the request is done
+    // (unlimited users still get one, so that callers need not care)
+    pub fn try_acquire(&self, uid: i32) -> Option<ConcurrencyPermit> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let count = in_flight.entry(uid).or_insert(0);
+        if self.max.map(|max| *count >= max).unwrap_or(false) {
+            return None;
+        }
+
+        *count += 1;
+        Some(ConcurrencyPermit {
+            uid,
+            in_flight: self.in_flight.clone()
+        })
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = in_flight.get_mut(&self.uid) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.uid);
+            }
+        }
+    }
+}
+
+// IP address of the client, if known
+// This is the remote end of the connection, unless that is one of
+// TRUSTED_PROXIES, in which case we believe what the proxy tells us
+// in `X-Forwarded-For` (or `X-Real-IP`). Anybody else could forge these.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    fn from_headers(request: &request::Request, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+        // Every proxy appends the address it received the request from,
+        // so the right-most one not added by our own proxies is the client
+        let forwarded = request.headers().get("X-Forwarded-For")
+            .flat_map(|v| v.split(','))
+            .filter_map(|x| x.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        if !forwarded.is_empty() {
+            return forwarded.iter()
+                .rev()
+                .find(|ip| !trusted_proxies.contains(ip))
+                .or_else(|| forwarded.first())
+                .cloned();
+        }
+
+        request.headers().get_one("X-Real-IP")
+            .and_then(|v| v.trim().parse().ok())
+    }
+}
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let remote = request.remote().map(|addr| addr.ip());
+        let config = request.guard::<State<Config>>().unwrap();
+        let ip = match remote {
+            Some(ip) if config.trusted_proxies.contains(&ip) =>
+                Self::from_headers(request, &config.trusted_proxies).or(remote),
+            _ => remote
+        };
+        request::Outcome::Success(ClientIp(ip))
+    }
+}
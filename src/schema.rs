@@ -1,3 +1,10 @@
+table! {
+    health_check (id) {
+        id -> Integer,
+        checked_at -> Timestamp,
+    }
+}
+
 table! {
     items (id) {
         id -> BigInt, // Forced, diesel does not support intepreting Integer as i64
@@ -9,6 +16,31 @@ table! {
         deleted -> Bool,
         created_at -> Text,
         updated_at -> Nullable<Text>,
+        app_metadata -> Nullable<Text>,
+        user_seq -> BigInt,
+    }
+}
+
+table! {
+    large_contents (owner, uuid) {
+        owner -> Integer,
+        uuid -> Text,
+        content -> Text,
+    }
+}
+
+table! {
+    notice_acks (notice_id, user_id) {
+        notice_id -> Integer,
+        user_id -> Integer,
+    }
+}
+
+table! {
+    server_notices (id) {
+        id -> Integer,
+        message -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -29,14 +61,25 @@ table! {
         pw_cost -> Integer,
         pw_nonce -> Text,
         version -> Text,
+        suspended -> Bool,
+        last_sync -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        last_item_seq -> BigInt,
     }
 }
 
 joinable!(items -> users (owner));
+joinable!(large_contents -> users (owner));
+joinable!(notice_acks -> server_notices (notice_id));
+joinable!(notice_acks -> users (user_id));
 joinable!(tokens -> users (uid));
 
 allow_tables_to_appear_in_same_query!(
+    health_check,
     items,
+    large_contents,
+    notice_acks,
+    server_notices,
     tokens,
     users,
 );
@@ -2,34 +2,125 @@ use ring::aead::*;
 use ring::digest::*;
 use ring::pbkdf2::*;
 use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-// In the API endpoint `/items/sync`, we use `max_id` of the
-// current user as the sync token. However, this may be prone
-// to side-channel leakage since all users in database share
-// the same auto-incrementing ID. An attacker may be able to
-// call `/items/sync` with one update each time and extract
-// what others' are doing based on changes in ID.
-// Therefore, we should at least not send the ID as a token
-// in plain-text to the client.
+// In the API endpoint `/items/sync`, we use the maximum `user_seq`
+// of the current user as the sync token. Tokens used to carry the
+// global item ID instead, which was prone to side-channel leakage
+// since all users in database share the same auto-incrementing ID.
+// An attacker may have been able to call `/items/sync` with one update
+// each time and extract what others' are doing based on changes in ID.
+// That is why tokens are encrypted, which we keep doing anyway.
+
+// Tokens carrying a user_seq are marked with this prefix, so that
+// we can still tell the ones carrying a global ID apart
+const SEQ_PREFIX: &str = "s:";
+
+// What a token points to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncPosition {
+    // `user_seq` of the items of the user
+    Seq(i64),
+    // Global item ID, from tokens issued before user_seq existed
+    LegacyId(i64)
+}
+
+// Why a token could not be read
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TokenError {
+    // Not something we could have issued at all
+    Malformed,
+    // Shaped like a token, but not sealed with our key
+    Undecryptable
+}
+
+// Tokens are versioned by the hash function used to derive their key
+// (see `SYNC_TOKEN_HASH`), so that changing it does not invalidate
+// the tokens held by clients. SHA-256 tokens predate versioning
+// and thus carry no prefix.
+const SHA512_PREFIX: &str = "v2:";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TokenHash {
+    Sha256,
+    Sha512
+}
+
+impl std::str::FromStr for TokenHash {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<TokenHash, ()> {
+        match s {
+            "sha256" => Ok(TokenHash::Sha256),
+            "sha512" => Ok(TokenHash::Sha512),
+            _ => Err(())
+        }
+    }
+}
 
 lazy_static! {
-    static ref TOKEN_KEY: [u8; 32] = get_token_key();
+    static ref TOKEN_HASH: TokenHash = crate::config::env_parse("SYNC_TOKEN_HASH")
+        .unwrap_or(TokenHash::Sha256);
+    static ref TOKEN_KEY_SHA256: [u8; 32] = get_token_key(&SHA256);
+    static ref TOKEN_KEY_SHA512: [u8; 32] = get_token_key(&SHA512);
+}
+
+// Validate the configuration and derive keys at startup
+// instead of on the first sync request
+pub fn init() {
+    lazy_static::initialize(&TOKEN_HASH);
+    lazy_static::initialize(&TOKEN_KEY_SHA256);
+    lazy_static::initialize(&TOKEN_KEY_SHA512);
 }
 
-pub fn get_token_key() -> [u8; 32] {
+pub fn get_token_key(hash: &'static ring::digest::Algorithm) -> [u8; 32] {
     let pwd = std::env::var("SYNC_TOKEN_SECRET")
         .expect("Please set SYNC_TOKEN_SECRET").into_bytes();
     let salt = std::env::var("SYNC_TOKEN_SALT")
         .expect("Please set SYNC_TOKEN_SALT").into_bytes();
+    derive_token_key(hash, &pwd, &salt)
+}
+
+pub fn derive_token_key(hash: &'static ring::digest::Algorithm, pwd: &[u8], salt: &[u8]) -> [u8; 32] {
     let mut ret = [0; 32];
-    derive(&SHA256, 100, &salt, &pwd, &mut ret);
+    derive(hash, 100, salt, pwd, &mut ret);
     ret
 }
 
+fn token_key(hash: TokenHash) -> &'static [u8; 32] {
+    match hash {
+        TokenHash::Sha256 => &TOKEN_KEY_SHA256,
+        TokenHash::Sha512 => &TOKEN_KEY_SHA512
+    }
+}
+
 pub fn max_id_to_token(max_id: i64) -> String {
-    let sealing_key = SealingKey::new(&CHACHA20_POLY1305, &*TOKEN_KEY).unwrap();
+    max_id_to_token_with(*TOKEN_HASH, max_id)
+}
+
+pub fn max_id_to_token_with(hash: TokenHash, max_id: i64) -> String {
+    let sealed = seal_max_id(token_key(hash), max_id);
+    match hash {
+        TokenHash::Sha256 => sealed,
+        TokenHash::Sha512 => format!("{}{}", SHA512_PREFIX, sealed)
+    }
+}
+
+// Encrypt `max_id` with `key`, without any version prefix
+pub fn seal_max_id(key: &[u8; 32], max_id: i64) -> String {
+    seal_max_id_with(key, max_id, |nonce| SystemRandom::new().fill(nonce).unwrap())
+}
+
+// Encrypt `max_id` with a nonce produced by `gen_nonce`
+pub fn seal_max_id_with(key: &[u8; 32], max_id: i64, gen_nonce: impl FnOnce(&mut [u8; 12])) -> String {
+    let sealing_key = SealingKey::new(&CHACHA20_POLY1305, key).unwrap();
     let mut nonce = [0u8; 12];
-    SystemRandom::new().fill(&mut nonce).unwrap();
+    gen_nonce(&mut nonce);
+    #[cfg(debug_assertions)]
+    nonce_check::record(&nonce);
     let mut id_str = max_id.to_string().as_bytes().to_vec();
     id_str.resize(id_str.len() + CHACHA20_POLY1305.tag_len(), 0);
     let out_len = seal_in_place(&sealing_key, &nonce, &[], &mut id_str, CHACHA20_POLY1305.tag_len())
@@ -39,20 +130,128 @@ pub fn max_id_to_token(max_id: i64) -> String {
     hex::encode(out)
 }
 
-pub fn token_to_max_id(token: &str) -> Result<i64, ()> {
-    let opening_key = OpeningKey::new(&CHACHA20_POLY1305, &*TOKEN_KEY).unwrap();
-    let data = hex::decode(token).map_err(|_| ())?;
+pub fn token_to_max_id(token: &str) -> Result<i64, TokenError> {
+    let (hash, token) = match token.strip_prefix(SHA512_PREFIX) {
+        Some(t) => (TokenHash::Sha512, t),
+        None => (TokenHash::Sha256, token)
+    };
+    let opening_key = OpeningKey::new(&CHACHA20_POLY1305, token_key(hash)).unwrap();
+    let data = hex::decode(token).map_err(|_| TokenError::Malformed)?;
     let len = data.len();
-    if len <= 12 {
-        return Err(());
+    if len <= 12 + CHACHA20_POLY1305.tag_len() {
+        return Err(TokenError::Malformed);
     }
 
     let mut id_str = (&data[0..(len - 12)]).to_vec();
     let nonce = &data[(len - 12)..len];
     let decrypted = open_in_place(&opening_key, nonce, &[], 0, &mut id_str)
-        .map_err(|_| ())?;
+        .map_err(|_| TokenError::Undecryptable)?;
     String::from_utf8(decrypted.to_vec())
-        .map_err(|_| ())?
+        .map_err(|_| TokenError::Malformed)?
         .parse()
-        .map_err(|_| ())
+        .map_err(|_| TokenError::Malformed)
+}
+
+pub fn seq_to_token(seq: i64) -> String {
+    format!("{}{}", SEQ_PREFIX, max_id_to_token(seq))
+}
+
+pub fn token_to_position(token: &str) -> Result<SyncPosition, TokenError> {
+    match token.strip_prefix(SEQ_PREFIX) {
+        Some(t) => token_to_max_id(t).map(SyncPosition::Seq),
+        None => token_to_max_id(token).map(SyncPosition::LegacyId)
+    }
+}
+
+// How long users with tokens that cannot be decrypted are remembered
+const DECRYPTION_WINDOW: Duration = Duration::from_secs(10 * 60);
+// Failures of fewer users than this within the window are never a cluster
+const MIN_FAILING_USERS: usize = 3;
+
+// Users whose tokens could not be decrypted recently
+// Once SYNC_TOKEN_SECRET (or the salt) has been changed, none of the tokens
+// held by clients can be decrypted anymore, while a single user failing,
+// however often, is more likely a broken client. So we only tell clients to
+// start over when the failures of several users cluster. From then on, every
+// such token is taken for one from before the change, as devices that come
+// online later still hold them, no matter how many others have moved on.
+pub struct DecryptionFailures {
+    users: Mutex<HashMap<i32, Instant>>,
+    secret_changed: AtomicBool
+}
+
+impl DecryptionFailures {
+    pub fn new() -> DecryptionFailures {
+        DecryptionFailures {
+            users: Mutex::new(HashMap::new()),
+            secret_changed: AtomicBool::new(false)
+        }
+    }
+
+    // Record a failure of user `uid`, and return whether
+    // SYNC_TOKEN_SECRET seems to have been changed
+    pub fn record(&self, uid: i32) -> bool {
+        if self.secret_changed.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        users.retain(|_, t| t.elapsed() < DECRYPTION_WINDOW);
+        users.insert(uid, Instant::now());
+        if users.len() >= MIN_FAILING_USERS {
+            self.secret_changed.store(true, Ordering::SeqCst);
+            users.clear();
+            return true;
+        }
+        false
+    }
+}
+
+// Reusing a nonce with the same key breaks ChaCha20-Poly1305, and
+// random 96-bit nonces never collide in practice, so any collision
+// means that the RNG is broken. Debug builds keep the recent nonces
+// around to catch that; release builds do not pay for it.
+#[cfg(debug_assertions)]
+pub mod nonce_check {
+    use std::collections::{HashSet, VecDeque};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // How many of the most recent nonces to remember
+    const MAX_RECENT_NONCES: usize = 4096;
+
+    #[derive(Default)]
+    struct RecentNonces {
+        order: VecDeque<[u8; 12]>,
+        seen: HashSet<[u8; 12]>
+    }
+
+    lazy_static! {
+        static ref RECENT_NONCES: Mutex<RecentNonces> = Mutex::new(RecentNonces::default());
+    }
+
+    static REUSED_NONCES: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn record(nonce: &[u8; 12]) {
+        let mut recent = RECENT_NONCES.lock().unwrap_or_else(|e| e.into_inner());
+        if !recent.seen.insert(*nonce) {
+            REUSED_NONCES.fetch_add(1, Ordering::Relaxed);
+            eprintln!("WARNING: sync token nonce {} was used twice; is the system RNG broken?",
+                hex::encode(nonce));
+            return;
+        }
+
+        recent.order.push_back(*nonce);
+        if recent.order.len() > MAX_RECENT_NONCES {
+            if let Some(oldest) = recent.order.pop_front() {
+                recent.seen.remove(&oldest);
+            }
+        }
+    }
+
+    // How many reused nonces have been seen so far
+    #[cfg(test)]
+    pub fn reused_nonces() -> usize {
+        REUSED_NONCES.load(Ordering::Relaxed)
+    }
 }
\ No newline at end of file
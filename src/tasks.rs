@@ -0,0 +1,84 @@
+use crate::{BusyWaitSqliteConnection, SqliteLike};
+use crate::api::SyncDedup;
+use crate::config::Config;
+use crate::item;
+use crate::lock::UserLock;
+use crate::user;
+use std::thread;
+use std::time::Duration;
+
+// How often the background maintenance tasks run
+const TASK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Spawn a thread running periodic maintenance tasks
+// The thread opens its own database connection instead of
+// taking one from the pool used by requests
+pub fn spawn(config: Config, lock: UserLock, dedup: SyncDedup) {
+    thread::spawn(move || {
+        let db = <BusyWaitSqliteConnection as diesel::Connection>::establish(&crate::db_path())
+            .expect("Could not connect to Database");
+        loop {
+            if let Some(days) = config.item_max_age_days {
+                expire_items(&db, &lock, &dedup, days);
+            }
+            purge_deleted_accounts(&db, &lock, config.account_retention_days);
+            thread::sleep(TASK_INTERVAL);
+        }
+    });
+}
+
+// Remove accounts deleted more than `days` days ago for good
+pub fn purge_deleted_accounts(db: &impl SqliteLike, lock: &UserLock, days: i64) {
+    let cutoff = (chrono::Utc::now() - chrono::Duration::days(days)).naive_utc();
+    let uids = match user::User::deleted_before(db, cutoff) {
+        Ok(uids) => uids,
+        Err(user::UserOpError(e)) => {
+            eprintln!("Account purge failed: {}", e);
+            return;
+        }
+    };
+
+    for uid in uids {
+        // Never interleave with a sync of the same user (see expire_items)
+        // and check again, as an admin may have restored it in the meantime
+        let mutex = lock.get_mutex(uid);
+        let _lock = lock.lock(&mutex);
+        match user::User::find_user_by_id(db, uid) {
+            Ok(ref u) if u.deleted_at.map(|t| t < cutoff).unwrap_or(false) => {
+                if let Err(user::UserOpError(e)) = u.purge(db) {
+                    eprintln!("Account purge failed for user {}: {}", uid, e);
+                }
+            },
+            _ => continue
+        }
+    }
+}
+
+// Tombstone every item created more than `days` days ago
+fn expire_items(db: &impl SqliteLike, lock: &UserLock, dedup: &SyncDedup, days: i64) {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(days);
+    let uids = match user::User::all_ids(db) {
+        Ok(uids) => uids,
+        Err(user::UserOpError(e)) => {
+            eprintln!("Item expiry failed: {}", e);
+            return;
+        }
+    };
+
+    for uid in uids {
+        let u = match user::User::find_user_by_id(db, uid) {
+            Ok(u) => u,
+            Err(_) => continue // Deleted in the meantime
+        };
+
+        // Tombstoning changes item IDs, so this must never
+        // interleave with a sync of the same user (see items_sync)
+        let mutex = lock.get_mutex(uid);
+        let _lock = lock.lock(&mutex);
+        match item::SyncItem::expire_items_of_user(db, &u, cutoff) {
+            Ok(0) => {},
+            Ok(_) => dedup.forget(uid),
+            Err(item::ItemOpError(e)) => eprintln!("Item expiry failed for user {}: {}", uid, e)
+        }
+    }
+}
@@ -0,0 +1,47 @@
+// Endpoints that help client developers exercise code paths
+// which are hard to reproduce against a real server.
+// Only compiled with the `testing` feature.
+use crate::SqliteLike;
+use crate::item;
+use crate::user;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+#[cfg(not(debug_assertions))]
+compile_error!("The `testing` feature must not be enabled in release builds");
+
+lazy_static! {
+    // (user id, item uuid) pairs whose next sync should conflict
+    static ref FORCED_CONFLICTS: Mutex<HashSet<(i32, String)>> = Mutex::new(HashSet::new());
+}
+
+pub fn routes() -> impl Into<Vec<rocket::Route>> {
+    routes![
+        force_conflict
+    ]
+}
+
+#[derive(Deserialize)]
+struct ForceConflictParams {
+    uuid: String
+}
+
+// Make the next sync of the item `uuid` report a conflict,
+// even if no other client has touched it in between
+#[post("/testing/force_conflict", format = "json", data = "<params>")]
+fn force_conflict(u: user::User, params: Json<ForceConflictParams>) -> Status {
+    FORCED_CONFLICTS.lock().unwrap().insert((u.id, params.into_inner().uuid));
+    Status::NoContent
+}
+
+// Returns the server version of the item if a conflict was forced on it.
+// Each forced conflict only fires once.
+pub fn forced_conflict(db: &impl SqliteLike, u: &user::User, uuid: &str) -> Option<item::SyncItem> {
+    if !FORCED_CONFLICTS.lock().unwrap().remove(&(u.id, uuid.to_string())) {
+        return None;
+    }
+    item::SyncItem::find_item_by_uuid(db, u, uuid).ok().map(|x| x.into())
+}
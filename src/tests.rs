@@ -11,6 +11,87 @@ fn get_test_client() -> Client {
 
 lazy_static! {
     static ref CLIENT: Client = get_test_client();
+    // Listed in ADMIN_EMAILS of .env.test
+    static ref ADMIN_TOKEN: String = register_user("admin@example.com");
+}
+
+fn get_test_db() -> crate::DbConn {
+    crate::DbConn::get_one(CLIENT.rocket())
+        .expect("database connection")
+}
+
+// Register a new user and return its token
+fn register_user(email: &str) -> String {
+    let mut resp = CLIENT.post("/auth")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({
+            "email": email,
+            "password": "testpw",
+            "pw_cost": 100,
+            "pw_nonce": "whatever",
+            "version": "001"
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    val.get("token").unwrap().as_str().unwrap().to_string()
+}
+
+// Sign in an existing user and return the new token
+fn sign_in(email: &str, password: &str) -> String {
+    let mut resp = CLIENT.post("/auth/sign_in")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({
+            "email": email,
+            "password": password
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    val.get("token").unwrap().as_str().unwrap().to_string()
+}
+
+fn sync_items(token: &str, params: serde_json::Value) -> serde_json::Value {
+    let (status, val) = sync_items_with(&CLIENT, token, params);
+    assert_eq!(status, Status::Ok);
+    val
+}
+
+// Sync through `client`, e.g. one built with a Config of its own,
+// leaving it to the caller to check the status
+fn sync_items_with(client: &Client, token: &str, params: serde_json::Value) -> (Status, serde_json::Value) {
+    let mut resp = client.post("/items/sync")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(params.to_string())
+        .dispatch();
+    let val = serde_json::from_str(&resp.body_string().unwrap()).unwrap();
+    (resp.status(), val)
+}
+
+// A note as a client would send it
+fn note(uuid: &str, content: &str) -> serde_json::Value {
+    serde_json::json!({
+        "uuid": uuid,
+        "content": content,
+        "content_type": "Note",
+        "enc_item_key": "key",
+        "created_at": "2020-02-22T00:00:00.000Z"
+    })
+}
+
+// `item` with some fields replaced (or added)
+fn with_fields(mut item: serde_json::Value, fields: serde_json::Value) -> serde_json::Value {
+    for (k, v) in fields.as_object().unwrap() {
+        item[k] = v.clone();
+    }
+    item
+}
+
+fn find_item<'a>(items: &'a serde_json::Value, uuid: &str) -> &'a serde_json::Value {
+    items.as_array().unwrap().iter()
+        .find(|it| it["uuid"] == uuid)
+        .expect("item should be present")
 }
 
 #[test]
@@ -47,6 +128,32 @@ fn sync_token_enc_dec_2() {
     assert_eq!(id, 1919810);
 }
 
+#[test]
+fn sync_token_dec_after_hash_change() {
+    use crate::sync_tokens::TokenHash;
+    dotenv::from_filename(".env.test").unwrap();
+    // Tokens carry the hash they were sealed with, so they
+    // still decrypt after SYNC_TOKEN_HASH is switched either way
+    let token = crate::sync_tokens::max_id_to_token_with(TokenHash::Sha512, 114514);
+    assert_eq!(crate::sync_tokens::token_to_max_id(&token).unwrap(), 114514);
+    let token = crate::sync_tokens::max_id_to_token_with(TokenHash::Sha256, 1919810);
+    assert_eq!(crate::sync_tokens::token_to_max_id(&token).unwrap(), 1919810);
+}
+
+// The check only exists in debug builds (see sync_tokens::nonce_check)
+#[cfg(debug_assertions)]
+#[test]
+fn should_warn_on_reused_nonce() {
+    use crate::sync_tokens::{nonce_check, seal_max_id_with};
+    // A broken RNG that keeps returning the same bytes
+    let key = [0u8; 32];
+    let broken_rng = |nonce: &mut [u8; 12]| *nonce = [0x5a; 12];
+    let before = nonce_check::reused_nonces();
+    seal_max_id_with(&key, 1, broken_rng);
+    seal_max_id_with(&key, 2, broken_rng);
+    assert_eq!(nonce_check::reused_nonces(), before + 1);
+}
+
 
 #[test]
 fn should_add_user() {
@@ -110,6 +217,22 @@ fn should_not_add_user_invalid_email() {
     assert_eq!(resp.status(), Status::BadRequest);
 }
 
+#[test]
+fn should_not_add_user_overlong_email() {
+    let resp = CLIENT
+        .post("/auth")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({
+            "email": format!("{}@example.com", "a".repeat(250)),
+            "password": "testpw",
+            "pw_cost": 100,
+            "pw_nonce": "whatever",
+            "version": "001"
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::BadRequest);
+}
+
 #[test]
 fn should_log_in_successfully() {
     CLIENT.post("/auth")
@@ -284,4 +407,1881 @@ fn should_success_authorize() {
         .dispatch();
     assert_eq!(resp.status(), Status::Ok);
     assert_eq!(resp.body_string().unwrap(), "\"test7@example.com\"");
+}
+
+#[test]
+fn should_retry_token_on_collision() {
+    CLIENT.post("/auth")
+        .header(ContentType::JSON)
+        .body(r#"{
+            "email": "test8@example.com",
+            "password": "testpw",
+            "pw_cost": 100,
+            "pw_nonce": "whatever",
+            "version": "001"
+        }"#)
+        .dispatch()
+        .body_string()
+        .unwrap();
+    let db = get_test_db();
+    let u = crate::user::User::find_user_by_email(&db.0, "test8@example.com").unwrap();
+    let existing = crate::tokens::Token::create_token(&db.0, u.id, None).unwrap();
+    // Hand out a colliding id first, then a fresh one
+    let mut ids = vec![existing.clone(), "b1c5a1f0-4e7d-4c52-9d2e-6f0c1f8d2a11".to_string()].into_iter();
+    let token = crate::tokens::Token::create_token_with(&db.0, u.id, None, || ids.next().unwrap()).unwrap();
+    assert_eq!(token, "b1c5a1f0-4e7d-4c52-9d2e-6f0c1f8d2a11");
+    // A generator that only ever collides should give up with an error
+    assert!(crate::tokens::Token::create_token_with(&db.0, u.id, None, || existing.clone()).is_err());
+    std::mem::drop(db);
+    let resp = CLIENT.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+}
+
+#[test]
+fn should_expire_old_items() {
+    let token = register_user("test9@example.com");
+    sync_items(&token, serde_json::json!({
+        "items": [
+            with_fields(note("3b1e9c1e-7e0f-4f1e-8a47-000000000001", "old"),
+                serde_json::json!({ "created_at": "2000-01-01T00:00:00.000Z" })),
+            with_fields(note("3b1e9c1e-7e0f-4f1e-8a47-000000000002", "new"),
+                serde_json::json!({ "created_at": chrono::Utc::now().to_rfc3339() }))
+        ]
+    }));
+
+    let db = get_test_db();
+    let u = crate::user::User::find_user_by_email(&db.0, "test9@example.com").unwrap();
+    let expired = crate::item::SyncItem::expire_items_of_user(&db.0, &u,
+        chrono::Utc::now() - chrono::Duration::days(1)).unwrap();
+    assert_eq!(expired, 1);
+    std::mem::drop(db);
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    let old = find_item(&resp["retrieved_items"], "3b1e9c1e-7e0f-4f1e-8a47-000000000001");
+    assert_eq!(old["deleted"], true);
+    assert!(old["content"].is_null());
+    let new = find_item(&resp["retrieved_items"], "3b1e9c1e-7e0f-4f1e-8a47-000000000002");
+    assert_eq!(new["deleted"], false);
+    assert_eq!(new["content"], "new");
+}
+
+#[test]
+fn should_report_remaining_items() {
+    let token = register_user("test10@example.com");
+    let items: Vec<_> = (1..=5)
+        .map(|i| note(&format!("5f0c2d8a-0b7e-4a38-9b35-00000000000{}", i), "content"))
+        .collect();
+    sync_items(&token, serde_json::json!({ "items": items }));
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [], "limit": 2, "return_remaining": true
+    }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 2);
+    assert_eq!(resp["remaining"], 3);
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [], "limit": 2, "return_remaining": true,
+        "cursor_token": resp["cursor_token"]
+    }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 2);
+    assert_eq!(resp["remaining"], 1);
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [], "limit": 2, "return_remaining": true,
+        "cursor_token": resp["cursor_token"]
+    }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+    assert_eq!(resp["remaining"], 0);
+    assert!(resp["cursor_token"].is_null());
+
+    // Not reported unless requested
+    let resp = sync_items(&token, serde_json::json!({ "items": [], "limit": 2 }));
+    assert!(resp.get("remaining").is_none());
+}
+
+fn search_items(token: &str, query: &str) -> Vec<String> {
+    let mut resp = CLIENT.get(format!("/items/search?{}", query))
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let mut uuids: Vec<String> = val["items"].as_array().unwrap().iter()
+        .map(|it| it["uuid"].as_str().unwrap().to_string())
+        .collect();
+    uuids.sort();
+    uuids
+}
+
+#[test]
+fn should_search_items() {
+    let token = register_user("test11@example.com");
+    let before_save = (chrono::Utc::now() - chrono::Duration::seconds(1))
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    sync_items(&token, serde_json::json!({
+        "items": [
+            with_fields(note("a", "content"), serde_json::json!({ "created_at": "2019-01-01T00:00:00.000Z" })),
+            with_fields(note("b", "content"), serde_json::json!({
+                "content_type": "Tag",
+                "created_at": "2020-01-01T00:00:00.000Z"
+            })), {
+            "uuid": "c",
+            "content_type": "Note",
+            "deleted": true,
+            "created_at": "2021-01-01T00:00:00.000Z"
+        }]
+    }));
+
+    assert_eq!(search_items(&token, ""), vec!["a", "b", "c"]);
+    assert_eq!(search_items(&token, "content_type=Note"), vec!["a", "c"]);
+    assert_eq!(search_items(&token, "deleted=true"), vec!["c"]);
+    assert_eq!(search_items(&token, "deleted=false"), vec!["a", "b"]);
+    assert_eq!(search_items(&token, "content_type=Note&deleted=false"), vec!["a"]);
+    assert_eq!(search_items(&token, "created_after=2019-06-01T00:00:00Z"), vec!["b", "c"]);
+    assert_eq!(search_items(&token, "created_before=2020-06-01T00:00:00Z"), vec!["a", "b"]);
+    assert_eq!(search_items(&token,
+        "created_after=2019-06-01T00:00:00Z&created_before=2020-06-01T00:00:00Z"), vec!["b"]);
+    assert_eq!(search_items(&token,
+        "content_type=Note&created_after=2019-06-01T00:00:00Z&deleted=true"), vec!["c"]);
+    assert_eq!(search_items(&token, &format!("updated_after={}", before_save)), vec!["a", "b", "c"]);
+    assert!(search_items(&token, &format!("updated_before={}", before_save)).is_empty());
+
+    let resp = CLIENT.get("/items/search?created_after=yesterday")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::BadRequest);
+    let resp = CLIENT.get("/items/search?content_type=Note").dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+}
+
+#[test]
+fn should_clamp_sync_limit() {
+    let token = register_user("test12@example.com");
+    let items: Vec<_> = (1..=3)
+        .map(|i| note(&format!("9d7c61a2-8f1b-4d3e-a6c4-00000000000{}", i), "content"))
+        .collect();
+    sync_items(&token, serde_json::json!({ "items": items }));
+
+    // Zero, negative and huge limits all fall back to sane page sizes
+    for limit in &[0, -5, 1_000_000_000_000i64] {
+        let resp = sync_items(&token, serde_json::json!({ "items": [], "limit": limit }));
+        assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 3);
+        assert!(resp["cursor_token"].is_null());
+    }
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [], "limit": 1 }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+    assert!(resp["cursor_token"].is_string());
+
+    // There are not enough items above to hit the cap
+    use crate::api::{clamp_sync_limit, DEFAULT_ITEMS_PER_SYNC, MAX_ITEMS_PER_SYNC};
+    assert_eq!(clamp_sync_limit(Some(1_000_000_000_000)), Some(MAX_ITEMS_PER_SYNC));
+    assert_eq!(clamp_sync_limit(Some(MAX_ITEMS_PER_SYNC + 1)), Some(MAX_ITEMS_PER_SYNC));
+    assert_eq!(clamp_sync_limit(Some(0)), Some(DEFAULT_ITEMS_PER_SYNC));
+    assert_eq!(clamp_sync_limit(Some(-5)), Some(DEFAULT_ITEMS_PER_SYNC));
+    assert_eq!(clamp_sync_limit(Some(42)), Some(42));
+    assert_eq!(clamp_sync_limit(None), None);
+}
+
+#[test]
+fn should_report_server_name() {
+    let mut resp = CLIENT.get("/version").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.headers().get_one("X-Server-Name"), Some("sfrs-test"));
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["server"], "sfrs-test");
+    assert_eq!(val["version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn should_describe_all_routes_in_openapi() {
+    let mut resp = CLIENT.get("/openapi.json").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.content_type(), Some(ContentType::JSON));
+    let doc = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let paths = doc["paths"].as_object().unwrap();
+    let routes: Vec<rocket::Route> = crate::api::routes().into();
+    for route in routes {
+        // `/items/<uuid>` in Rocket is `/items/{uuid}` in OpenAPI
+        let path = route.uri.path().replace("<", "{").replace(">", "}");
+        let method = route.method.as_str().to_lowercase();
+        assert!(paths.get(&path).and_then(|p| p.get(&method)).is_some(),
+            "{} {} is missing from openapi.json", method, path);
+    }
+}
+
+#[test]
+fn should_reset_sync_for_future_sync_token() {
+    let token = register_user("test13@example.com");
+    sync_items(&token, serde_json::json!({
+        "items": [note("0e5f4b36-2f40-4a0c-8d8b-000000000001", "content")]
+    }));
+
+    let (status, val) = sync_items_with(&CLIENT, &token, serde_json::json!({
+        "items": [],
+        "sync_token": crate::sync_tokens::max_id_to_token(1_000_000_000_000)
+    }));
+    assert_eq!(status, Status::Gone);
+    assert!(!val["errors"].as_array().unwrap().is_empty());
+    assert_eq!(val["code"], "reset_sync");
+
+    // Dropping the sync_token gets the client going again
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn should_return_json_status_on_index() {
+    let mut resp = CLIENT.get("/")
+        .header(Header::new("Accept", "application/json"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.content_type(), Some(ContentType::JSON));
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val, serde_json::json!({ "server": "sfrs-test", "status": "ok" }));
+}
+
+#[test]
+fn should_return_text_status_on_index() {
+    for accept in &[Some("text/html,application/xhtml+xml,*/*;q=0.8"), Some("*/*"), None] {
+        let mut req = CLIENT.get("/");
+        if let Some(accept) = accept {
+            req.add_header(Header::new("Accept", *accept));
+        }
+        let mut resp = req.dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.content_type(), Some(ContentType::Plain));
+        assert_eq!(resp.body_string().unwrap(), "sfrs-test: ok\n");
+    }
+}
+
+#[test]
+fn should_save_conflict_resolutions() {
+    let token = register_user("test14@example.com");
+    let uuid = "6c1c0b1e-8e3a-4c1f-9f57-000000000001";
+    let item = |content: &str| note(uuid, content);
+    let resp = sync_items(&token, serde_json::json!({ "items": [item("v1")] }));
+    let old_token = resp["sync_token"].as_str().unwrap().to_string();
+    sync_items(&token, serde_json::json!({
+        "items": [item("v2")],
+        "sync_token": old_token
+    }));
+
+    // Another client still at the old sync_token gets a conflict
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("client")],
+        "sync_token": old_token
+    }));
+    assert_eq!(resp["conflicts"].as_array().unwrap().len(), 1);
+
+    // ...and sends back its merged version
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "resolve_conflicts": [item("merged")],
+        "sync_token": old_token
+    }));
+    assert!(resp["conflicts"].as_array().unwrap().is_empty());
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+    assert_eq!(find_item(&resp["saved_items"], uuid)["content"], "merged");
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(find_item(&resp["retrieved_items"], uuid)["content"], "merged");
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn should_report_forced_conflict() {
+    let token = register_user("test15@example.com");
+    let uuid = "3a9d2f4e-5b6c-4d7e-8f90-000000000001";
+    let item = note(uuid, "content");
+    let resp = sync_items(&token, serde_json::json!({ "items": [item] }));
+    let sync_token = resp["sync_token"].as_str().unwrap().to_string();
+
+    let resp = CLIENT.post("/testing/force_conflict")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "uuid": uuid }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::NoContent);
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item],
+        "sync_token": sync_token
+    }));
+    let conflicts = resp["conflicts"].as_array().unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0]["server_item"]["uuid"], uuid);
+    assert!(resp["saved_items"].as_array().unwrap().is_empty());
+
+    // The forced conflict only fires once
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item],
+        "sync_token": sync_token
+    }));
+    assert!(resp["conflicts"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_export_items_in_pages() {
+    let token = register_user("test16@example.com");
+    let items: Vec<_> = (0..5)
+        .map(|i| with_fields(note(&format!("9b8e7d6c-5a4b-4c3d-8e2f-00000000000{}", i), "content"),
+            serde_json::json!({ "deleted": i == 4 })))
+        .collect();
+    sync_items(&token, serde_json::json!({ "items": items }));
+
+    let mut exported: Vec<String> = vec![];
+    let mut cursor: Option<String> = None;
+    loop {
+        let url = match cursor {
+            None => "/items/export?limit=2".to_string(),
+            Some(ref c) => format!("/items/export?limit=2&cursor={}", c)
+        };
+        let mut resp = CLIENT.get(url)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+        let page = val["items"].as_array().unwrap();
+        assert!(page.len() <= 2);
+        exported.extend(page.iter().map(|it| it["uuid"].as_str().unwrap().to_string()));
+        match val["cursor"].as_str() {
+            Some(c) => cursor = Some(c.to_string()),
+            None => break
+        }
+    }
+
+    // Every item exactly once, without the deleted one
+    let expected: Vec<String> = (0..4)
+        .map(|i| format!("9b8e7d6c-5a4b-4c3d-8e2f-00000000000{}", i))
+        .collect();
+    assert_eq!(exported, expected);
+}
+
+#[test]
+fn should_omit_saved_items_on_request() {
+    let token = register_user("test17@example.com");
+    let item = |uuid: &str| note(uuid, "content");
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("4d3c2b1a-0f9e-4d8c-b7a6-000000000001")]
+    }));
+    let old_token = resp["sync_token"].as_str().unwrap().to_string();
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("4d3c2b1a-0f9e-4d8c-b7a6-000000000002")],
+        "sync_token": old_token,
+        "return_saved": false
+    }));
+    assert!(resp.get("saved_items").is_none());
+    let new_token = resp["sync_token"].as_str().unwrap();
+    assert_ne!(new_token, old_token);
+
+    // The new token already covers the item we saved
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "sync_token": new_token
+    }));
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_throttle_registration_per_ip() {
+    let register_from = |email: &str, addr: &str| {
+        CLIENT.post("/auth")
+            .header(ContentType::JSON)
+            .remote(addr.parse().unwrap())
+            .body(serde_json::json!({
+                "email": email,
+                "password": "testpw",
+                "pw_cost": 100,
+                "pw_nonce": "whatever",
+                "version": "001"
+            }).to_string())
+            .dispatch()
+            .status()
+    };
+    assert_eq!(register_from("test18a@example.com", "192.0.2.1:1234"), Status::Ok);
+    assert_eq!(register_from("test18b@example.com", "192.0.2.1:4321"), Status::TooManyRequests);
+    assert_eq!(register_from("test18b@example.com", "192.0.2.2:1234"), Status::Ok);
+
+    // Trusted proxies are exempt
+    assert_eq!(register_from("test18c@example.com", "10.0.0.1:1234"), Status::Ok);
+    assert_eq!(register_from("test18d@example.com", "10.0.0.1:1234"), Status::Ok);
+}
+
+#[test]
+fn should_let_one_of_concurrent_registrations_through() {
+    let limiter = std::sync::Arc::new(
+        crate::ratelimit::RateLimiter::new(std::time::Duration::from_secs(60)));
+    let ip: std::net::IpAddr = "192.0.2.3".parse().unwrap();
+    let threads: Vec<_> = (0..8).map(|_| {
+        let limiter = limiter.clone();
+        std::thread::spawn(move || limiter.try_record(ip))
+    }).collect();
+    let recorded = threads.into_iter()
+        .map(|t| t.join().unwrap())
+        .filter(|recorded| *recorded)
+        .count();
+    assert_eq!(recorded, 1);
+
+    // Failed registrations do not count
+    limiter.forget(&ip);
+    assert!(limiter.try_record(ip));
+}
+
+#[test]
+fn should_honor_forwarded_ip_from_trusted_proxy() {
+    let register_from = |email: &str, addr: &str, forwarded: &str| {
+        CLIENT.post("/auth")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Forwarded-For", forwarded.to_string()))
+            .remote(addr.parse().unwrap())
+            .body(serde_json::json!({
+                "email": email,
+                "password": "testpw",
+                "pw_cost": 100,
+                "pw_nonce": "whatever",
+                "version": "001"
+            }).to_string())
+            .dispatch()
+            .status()
+    };
+    // Behind the proxy, the forwarded address is limited
+    assert_eq!(register_from("test32a@example.com", "10.0.0.1:1234", "192.0.2.50"), Status::Ok);
+    assert_eq!(register_from("test32b@example.com", "10.0.0.1:1234", "192.0.2.50"), Status::TooManyRequests);
+    assert_eq!(register_from("test32b@example.com", "10.0.0.1:1234", "192.0.2.99, 192.0.2.51"), Status::Ok);
+
+    // Anybody else cannot pick their address
+    assert_eq!(register_from("test32c@example.com", "192.0.2.60:1234", "192.0.2.61"), Status::Ok);
+    assert_eq!(register_from("test32d@example.com", "192.0.2.60:1234", "192.0.2.62"), Status::TooManyRequests);
+    assert_eq!(register_from("test32d@example.com", "192.0.2.63:1234", "192.0.2.60"), Status::Ok);
+}
+
+#[test]
+fn should_return_json_error_on_guard_failure() {
+    let mut resp = CLIENT.get("/auth/ping").dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+    assert_eq!(resp.content_type(), Some(ContentType::JSON));
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val, serde_json::json!({ "errors": ["Token missing"], "code": "unauthorized" }));
+
+    let mut resp = CLIENT.get("/auth/ping")
+        .header(Header::new("Authorization", "Bearer nope"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "unauthorized");
+    assert_eq!(val["errors"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn should_round_trip_app_metadata() {
+    let token = register_user("test19@example.com");
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [
+            with_fields(note("1f2e3d4c-5b6a-4978-8695-000000000001", "content"),
+                serde_json::json!({ "app_metadata": "{\"protected\":true}" })),
+            note("1f2e3d4c-5b6a-4978-8695-000000000002", "content")
+        ]
+    }));
+    assert_eq!(resp["saved_items"].as_array().unwrap().len(), 2);
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    let items = &resp["retrieved_items"];
+    assert_eq!(find_item(items, "1f2e3d4c-5b6a-4978-8695-000000000001")["app_metadata"], "{\"protected\":true}");
+    assert!(find_item(items, "1f2e3d4c-5b6a-4978-8695-000000000002").get("app_metadata").is_none());
+}
+
+#[test]
+fn should_only_accept_deletions_when_db_is_full() {
+    let token = register_user("test20@example.com");
+    let item = |uuid: &str, deleted: bool|
+        with_fields(note(uuid, "content"), serde_json::json!({ "deleted": deleted }));
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("7e6d5c4b-3a29-4180-9f8e-000000000001", false)]
+    }));
+    let sync_token = resp["sync_token"].as_str().unwrap().to_string();
+
+    // Any database is larger than one byte
+    let mut config = crate::config::Config::from_env();
+    config.max_db_size_bytes = Some(1);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let sync_with_full_db = |items: serde_json::Value| sync_items_with(&client, &token,
+        serde_json::json!({ "items": items, "sync_token": sync_token }));
+
+    let (status, val) = sync_with_full_db(serde_json::json!([item("7e6d5c4b-3a29-4180-9f8e-000000000002", false)]));
+    assert_eq!(status, Status::InsufficientStorage);
+    assert_eq!(val["code"], "insufficient_storage");
+
+    let (status, _) = sync_with_full_db(serde_json::json!([item("7e6d5c4b-3a29-4180-9f8e-000000000001", true)]));
+    assert_eq!(status, Status::Ok);
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    let items = &resp["retrieved_items"];
+    assert_eq!(items.as_array().unwrap().len(), 1);
+    assert_eq!(find_item(items, "7e6d5c4b-3a29-4180-9f8e-000000000001")["deleted"], true);
+}
+
+#[test]
+fn should_report_database_health() {
+    let mut resp = CLIENT.get("/healthz").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val, serde_json::json!({ "read_ok": true, "write_ok": true }));
+}
+
+#[test]
+fn should_return_all_items_keys() {
+    let token = register_user("test21@example.com");
+    let item = |uuid: &str, content_type: &str|
+        with_fields(note(uuid, "content"), serde_json::json!({ "content_type": content_type }));
+    sync_items(&token, serde_json::json!({
+        "items": [
+            item("2b3c4d5e-6f70-4182-93a4-000000000001", "SN|ItemsKey"),
+            item("2b3c4d5e-6f70-4182-93a4-000000000002", "Note")
+        ]
+    }));
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("2b3c4d5e-6f70-4182-93a4-000000000003", "SN|ItemsKey")]
+    }));
+
+    // Both keys are older than this sync_token, but still returned
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "sync_token": resp["sync_token"]
+    }));
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+
+    let mut resp = CLIENT.get("/items/keys")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let mut uuids: Vec<&str> = val["items"].as_array().unwrap().iter()
+        .map(|it| it["uuid"].as_str().unwrap())
+        .collect();
+    uuids.sort();
+    assert_eq!(uuids, vec![
+        "2b3c4d5e-6f70-4182-93a4-000000000001",
+        "2b3c4d5e-6f70-4182-93a4-000000000003"
+    ]);
+}
+
+#[test]
+fn should_reject_wildcard_origins_with_credentials() {
+    dotenv::from_filename(".env.test").unwrap();
+    let mut config = crate::config::Config::from_env();
+    config.cors_allow_credentials = true;
+    config.cors_allowed_origins = vec![];
+    assert!(crate::build_cors(&config).is_err());
+    config.cors_allowed_origins = vec!["https://app.example.com".into(), "*".into()];
+    assert!(crate::build_cors(&config).is_err());
+    config.cors_allowed_origins = vec!["https://app.example.com".into()];
+    assert!(crate::build_cors(&config).is_ok());
+}
+
+#[test]
+fn should_suspend_and_reinstate_accounts() {
+    let token = register_user("test22@example.com");
+    let suspend = |admin_token: &str, suspended: bool| {
+        CLIENT.post("/admin/suspend")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", admin_token)))
+            .body(serde_json::json!({
+                "email": "test22@example.com",
+                "suspended": suspended
+            }).to_string())
+            .dispatch()
+            .status()
+    };
+    let sign_in = |password: &str| {
+        CLIENT.post("/auth/sign_in")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({
+                "email": "test22@example.com",
+                "password": password
+            }).to_string())
+            .dispatch()
+    };
+
+    // Only admins can suspend
+    assert_eq!(suspend(&token, true), Status::Forbidden);
+    assert_eq!(suspend(&ADMIN_TOKEN, true), Status::NoContent);
+
+    // Existing tokens stop working immediately
+    let mut resp = CLIENT.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Forbidden);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "account_suspended");
+    let (status, _) = sync_items_with(&CLIENT, &token, serde_json::json!({ "items": [] }));
+    assert_eq!(status, Status::Forbidden);
+
+    let mut resp = sign_in("testpw");
+    assert_eq!(resp.status(), Status::Forbidden);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "account_suspended");
+
+    assert_eq!(suspend(&ADMIN_TOKEN, false), Status::NoContent);
+    assert_eq!(sign_in("testpw").status(), Status::Ok);
+    let resp = CLIENT.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+}
+
+#[test]
+fn should_update_key_params_on_change_pw() {
+    register_user("test23@example.com");
+    let change_pw = |params: serde_json::Value| {
+        CLIENT.post("/auth/change_pw")
+            .header(ContentType::JSON)
+            .body(params.to_string())
+            .dispatch()
+            .status()
+    };
+
+    // Invalid parameters are rejected before anything is changed
+    assert_eq!(change_pw(serde_json::json!({
+        "email": "test23@example.com",
+        "password": "testpw1",
+        "current_password": "testpw",
+        "pw_cost": 0
+    })), Status::BadRequest);
+    assert_eq!(change_pw(serde_json::json!({
+        "email": "test23@example.com",
+        "password": "testpw1",
+        "current_password": "testpw",
+        "version": "999"
+    })), Status::BadRequest);
+
+    assert_eq!(change_pw(serde_json::json!({
+        "email": "test23@example.com",
+        "password": "testpw1",
+        "current_password": "testpw",
+        "pw_cost": 110000,
+        "pw_nonce": "new_nonce",
+        "version": "004"
+    })), Status::NoContent);
+
+    let mut resp = CLIENT.get("/auth/params?email=test23@example.com").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val, serde_json::json!({
+        "pw_cost": 110000,
+        "pw_nonce": "new_nonce",
+        "version": "004"
+    }));
+}
+
+#[test]
+fn should_normalize_timestamps_on_read() {
+    let token = register_user("test24@example.com");
+    let created_at = [
+        ("5a6b7c8d-9e0f-4a1b-8c2d-000000000001", "2020-02-22T08:00:00+08:00", "2020-02-22T00:00:00.000Z"),
+        ("5a6b7c8d-9e0f-4a1b-8c2d-000000000002", "2020-02-22 00:00:00", "2020-02-22T00:00:00.000Z"),
+        ("5a6b7c8d-9e0f-4a1b-8c2d-000000000003", "2020-02-22T00:00:00.123456Z", "2020-02-22T00:00:00.123Z"),
+        ("5a6b7c8d-9e0f-4a1b-8c2d-000000000004", "Sat, 22 Feb 2020 00:00:00 +0000", "2020-02-22T00:00:00.000Z"),
+        ("5a6b7c8d-9e0f-4a1b-8c2d-000000000005", "yesterday", "yesterday")
+    ];
+    let items: Vec<_> = created_at.iter()
+        .map(|(uuid, t, _)| with_fields(note(uuid, "content"), serde_json::json!({ "created_at": t })))
+        .collect();
+    sync_items(&token, serde_json::json!({ "items": items }));
+
+    // Byte-exact by default
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    for (uuid, t, _) in created_at.iter() {
+        assert_eq!(find_item(&resp["retrieved_items"], uuid)["created_at"], *t);
+    }
+
+    let mut config = crate::config::Config::from_env();
+    config.normalize_timestamps = true;
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let (status, val) = sync_items_with(&client, &token, serde_json::json!({ "items": [] }));
+    assert_eq!(status, Status::Ok);
+    for (uuid, _, normalized) in created_at.iter() {
+        assert_eq!(find_item(&val["retrieved_items"], uuid)["created_at"], *normalized);
+    }
+}
+
+#[test]
+fn should_evict_oldest_sessions() {
+    let first = register_user("test25@example.com");
+    let db = get_test_db();
+    let u = crate::user::User::find_user_by_email(&db.0, "test25@example.com").unwrap();
+    let second = crate::tokens::Token::create_token(&db.0, u.id, Some(2)).unwrap();
+    assert!(crate::tokens::Token::find_token_by_id(&db.0, &first).is_some());
+    let third = crate::tokens::Token::create_token(&db.0, u.id, Some(2)).unwrap();
+    assert!(crate::tokens::Token::find_token_by_id(&db.0, &first).is_none());
+    assert!(crate::tokens::Token::find_token_by_id(&db.0, &second).is_some());
+    assert!(crate::tokens::Token::find_token_by_id(&db.0, &third).is_some());
+}
+
+#[test]
+fn should_check_existing_uuids() {
+    let token = register_user("test26@example.com");
+    let item = |uuid: &str, deleted: bool|
+        with_fields(note(uuid, "content"), serde_json::json!({ "deleted": deleted }));
+    sync_items(&token, serde_json::json!({
+        "items": [
+            item("8f7e6d5c-4b3a-4291-8a0b-000000000001", false),
+            item("8f7e6d5c-4b3a-4291-8a0b-000000000002", true)
+        ]
+    }));
+    // Items of other users do not count
+    let other = register_user("test26b@example.com");
+    sync_items(&other, serde_json::json!({
+        "items": [item("8f7e6d5c-4b3a-4291-8a0b-000000000003", false)]
+    }));
+
+    let mut resp = CLIENT.post("/items/exists")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({
+            "uuids": [
+                "8f7e6d5c-4b3a-4291-8a0b-000000000001",
+                "8f7e6d5c-4b3a-4291-8a0b-000000000002",
+                "8f7e6d5c-4b3a-4291-8a0b-000000000003"
+            ]
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["items"], serde_json::json!([
+        { "uuid": "8f7e6d5c-4b3a-4291-8a0b-000000000001", "exists": true, "deleted": false },
+        { "uuid": "8f7e6d5c-4b3a-4291-8a0b-000000000002", "exists": true, "deleted": true },
+        { "uuid": "8f7e6d5c-4b3a-4291-8a0b-000000000003", "exists": false, "deleted": false }
+    ]));
+}
+
+#[test]
+fn should_reset_sync_for_token_under_old_secret() {
+    // Our own DecryptionFailures, so that other tests do not count
+    let client = Client::new(crate::build_rocket_with_config(crate::config::Config::from_env()))
+        .expect("valid rocket instance");
+    let sync = |token: &str, sync_token: &str| {
+        let (status, val) = sync_items_with(&client, token,
+            serde_json::json!({ "items": [], "sync_token": sync_token }));
+        (status, val["code"].clone())
+    };
+    let users = ["test27@example.com", "test27b@example.com", "test27c@example.com"].iter()
+        .map(|email| register_user(email))
+        .collect::<Vec<_>>();
+
+    // Garbage is never taken for a token under another secret
+    for _ in 0..3 {
+        assert_eq!(sync(&users[0], "not a token").0, Status::BadRequest);
+    }
+
+    // Sealed before the operator changed SYNC_TOKEN_SECRET
+    let key = crate::sync_tokens::derive_token_key(&ring::digest::SHA256, b"old_password", b"awesome_salt");
+    let old_token = crate::sync_tokens::seal_max_id(&key, 1);
+    // A single user could just as well have a broken client
+    for _ in 0..5 {
+        assert_eq!(sync(&users[0], &old_token).0, Status::BadRequest);
+    }
+    assert_eq!(sync(&users[1], &old_token).0, Status::BadRequest);
+    let reset = (Status::Gone, serde_json::json!("reset_sync"));
+    assert_eq!(sync(&users[2], &old_token), reset);
+    // ...and from then on, everyone still holding such a token starts over
+    assert_eq!(sync(&users[0], &old_token), reset);
+    assert_eq!(sync(&users[0], &crate::sync_tokens::seq_to_token(0)).0, Status::Ok);
+}
+
+#[test]
+fn should_only_reset_sync_for_clustered_decryption_failures() {
+    let failures = crate::sync_tokens::DecryptionFailures::new();
+    for _ in 0..5 {
+        assert!(!failures.record(1));
+    }
+    assert!(!failures.record(2));
+    assert!(failures.record(3));
+    // The secret does not change back
+    assert!(failures.record(1));
+}
+
+#[test]
+fn should_advance_last_sync() {
+    let token = register_user("test28@example.com");
+    let last_sync = || {
+        let mut resp = CLIENT.get("/auth/me")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+        assert_eq!(val["email"], "test28@example.com");
+        val["last_sync"].as_str().map(|t| t.to_string())
+    };
+    assert_eq!(last_sync(), None);
+
+    sync_items(&token, serde_json::json!({ "items": [] }));
+    let first = last_sync().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    sync_items(&token, serde_json::json!({ "items": [] }));
+    let second = last_sync().unwrap();
+    assert!(second > first);
+
+    // Admins see it, too
+    let mut resp = CLIENT.get("/admin/users")
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let user = val["users"].as_array().unwrap().iter()
+        .find(|u| u["email"] == "test28@example.com")
+        .unwrap();
+    assert_eq!(user["last_sync"], second);
+}
+
+#[test]
+fn should_retry_until_database_is_available() {
+    // Pretend the database only shows up on the third attempt
+    let mut attempts = 0;
+    let res = crate::retry_with_backoff(5, std::time::Duration::from_millis(1), || {
+        attempts += 1;
+        if attempts < 3 {
+            Err("Could not connect to Database")
+        } else {
+            Ok(attempts)
+        }
+    });
+    assert_eq!(res, Ok(3));
+
+    // ...and give up once we run out of retries
+    let mut attempts = 0;
+    let res: Result<(), _> = crate::retry_with_backoff(2, std::time::Duration::from_millis(1), || {
+        attempts += 1;
+        Err("Could not connect to Database")
+    });
+    assert!(res.is_err());
+    assert_eq!(attempts, 3);
+}
+
+#[test]
+fn should_migrate_database_that_shows_up_late() {
+    // Like a volume that is still being mounted
+    let dir = std::env::temp_dir().join(format!("sfrs-late-db-{}", std::process::id()));
+    let url = dir.join("database.db").to_str().unwrap().to_string();
+    let creator = {
+        let dir = dir.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            std::fs::create_dir_all(&dir).unwrap();
+        })
+    };
+
+    let res = crate::run_db_migrations(&url, 5, std::time::Duration::from_millis(50));
+    creator.join().unwrap();
+    let migrated = std::path::Path::new(&url).exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(res, Ok(()));
+    assert!(migrated);
+}
+
+#[test]
+fn should_export_single_content_type() {
+    let token = register_user("test29@example.com");
+    let item = |uuid: &str, content_type: &str|
+        with_fields(note(uuid, "content"), serde_json::json!({ "content_type": content_type }));
+    sync_items(&token, serde_json::json!({
+        "items": [
+            item("0a1b2c3d-4e5f-4a6b-9c7d-000000000001", "Note"),
+            item("0a1b2c3d-4e5f-4a6b-9c7d-000000000002", "Tag"),
+            item("0a1b2c3d-4e5f-4a6b-9c7d-000000000003", "Note")
+        ]
+    }));
+
+    let mut resp = CLIENT.get("/items/export?content_type=Note")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let items = val["items"].as_array().unwrap();
+    assert_eq!(items.len(), 2);
+    assert!(items.iter().all(|it| it["content_type"] == "Note"));
+    assert!(val["cursor"].is_null());
+}
+
+// No known input makes our own handlers panic anymore (the ones that did,
+// syncing after a panic or with a poisoned database lock, are tested below),
+// so this stands in for whatever may still be lurking
+#[get("/test_panic")]
+fn panicking_route() -> &'static str {
+    panic!("test panic")
+}
+
+#[test]
+fn should_turn_panics_into_json_500() {
+    let client = Client::new(build_rocket()
+        .mount("/", crate::api::catch_panics(routes![panicking_route]))).unwrap();
+    let mut resp = client.get("/test_panic").dispatch();
+    assert_eq!(resp.status(), Status::InternalServerError);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "internal_server_error");
+    // The server keeps serving
+    assert_eq!(client.get("/").dispatch().status(), Status::Ok);
+}
+
+#[test]
+fn should_sync_after_panic_in_sync() {
+    let token = register_user("test30@example.com");
+    let uid = {
+        let db = get_test_db();
+        crate::user::User::find_user_by_token(&db.0, &token, None).unwrap().0.id
+    };
+    // Poison the per-user lock as a panicking sync would
+    let lock = CLIENT.rocket().state::<crate::lock::UserLock>().unwrap().clone();
+    let _ = std::thread::spawn(move || {
+        let mutex = lock.get_mutex(uid);
+        let _guard = mutex.lock().unwrap();
+        panic!("test panic");
+    }).join();
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn should_sync_after_panic_holding_database_lock() {
+    let token = register_user("test55@example.com");
+    // Poison the database lock as a handler panicking in the middle of a write would
+    let _ = std::thread::spawn(|| {
+        let _guard = crate::lock_db_for_write();
+        panic!("test panic");
+    }).join();
+    assert!(crate::DB_LOCK.is_poisoned());
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [note("af1b2c3d-4e5f-4a6b-9c7d-000000000001", "content")]
+    }));
+    assert!(resp["sync_token"].is_string());
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn should_not_panic_on_empty_conflict() {
+    let conflict = crate::api::SyncConflict {
+        conf_type: "sync_conflict".into(),
+        server_item: None,
+        unsaved_item: None
+    };
+    assert_eq!(conflict.uuid(), None);
+}
+
+#[test]
+fn should_retrieve_items_updated_after() {
+    let token = register_user("test31@example.com");
+    let item = |uuid: &str| note(uuid, "content");
+    sync_items(&token, serde_json::json!({
+        "items": [item("1a1b2c3d-4e5f-4a6b-9c7d-000000000001")]
+    }));
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let since = chrono::Utc::now().to_rfc3339();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    sync_items(&token, serde_json::json!({
+        "items": [item("1a1b2c3d-4e5f-4a6b-9c7d-000000000002")]
+    }));
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "updated_after": since
+    }));
+    let items = resp["retrieved_items"].as_array().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0]["uuid"], "1a1b2c3d-4e5f-4a6b-9c7d-000000000002");
+
+    let (status, _) = sync_items_with(&CLIENT, &token,
+        serde_json::json!({ "items": [], "updated_after": "yesterday" }));
+    assert_eq!(status, Status::BadRequest);
+}
+
+#[test]
+fn should_not_save_items_along_with_updated_after() {
+    let token = register_user("test56@example.com");
+    let uuid = "b01b2c3d-4e5f-4a6b-9c7d-000000000001";
+    let resp = sync_items(&token, serde_json::json!({ "items": [note(uuid, "first")] }));
+    let sync_token = resp["sync_token"].clone();
+    // Another device changes the item
+    sync_items(&token, serde_json::json!({ "items": [note(uuid, "other device")], "sync_token": sync_token }));
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let since = chrono::Utc::now().to_rfc3339();
+
+    // The change is not retrieved, so it would be overwritten without a conflict
+    for params in &[
+        serde_json::json!({ "items": [note(uuid, "this device")] }),
+        serde_json::json!({ "items": [], "resolve_conflicts": [note(uuid, "this device")] })
+    ] {
+        let mut params = params.clone();
+        params["sync_token"] = sync_token.clone();
+        params["updated_after"] = serde_json::json!(since);
+        let (status, _) = sync_items_with(&CLIENT, &token, params);
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(find_item(&resp["retrieved_items"], uuid)["content"], "other device");
+}
+
+fn conflicts_metric(client: &Client) -> u64 {
+    let mut resp = client.get("/metrics").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    resp.body_string().unwrap()
+        .lines()
+        .find(|l| l.starts_with("sfrs_sync_conflicts_total "))
+        .and_then(|l| l.split(' ').nth(1))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn should_count_conflicts_in_metrics() {
+    let token = register_user("test33@example.com");
+    let item = |content: &str| note("2a1b2c3d-4e5f-4a6b-9c7d-000000000001", content);
+    let resp = sync_items(&token, serde_json::json!({ "items": [item("v1")] }));
+    let old_token = resp["sync_token"].as_str().unwrap().to_string();
+    sync_items(&token, serde_json::json!({
+        "items": [item("v2")],
+        "sync_token": old_token
+    }));
+
+    // Other tests may produce conflicts at the same time,
+    // so count them on a server of our own
+    let client = Client::new(crate::build_rocket_with_config(crate::config::Config::from_env()))
+        .expect("valid rocket instance");
+    let (status, resp) = sync_items_with(&client, &token, serde_json::json!({
+        "items": [item("client")],
+        "sync_token": old_token
+    }));
+    assert_eq!(status, Status::Ok);
+    assert_eq!(resp["conflicts"].as_array().unwrap().len(), 1);
+    assert_eq!(conflicts_metric(&client), 1);
+}
+
+#[test]
+fn should_seed_welcome_item() {
+    let mut config = crate::config::Config::from_env();
+    config.seed_welcome_item = Some("welcome".into());
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let mut resp = client.post("/auth")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({
+            "email": "test34@example.com",
+            "password": "testpw",
+            "pw_cost": 100,
+            "pw_nonce": "whatever",
+            "version": "001"
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let token = val["token"].as_str().unwrap();
+
+    let resp = sync_items(token, serde_json::json!({ "items": [] }));
+    let items = resp["retrieved_items"].as_array().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0]["content"], "welcome");
+    assert_eq!(items[0]["content_type"], "Note");
+
+    // Not seeded unless configured
+    let token = register_user("test34b@example.com");
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_sync_metadata_only() {
+    let token = register_user("test35@example.com");
+    sync_items(&token, serde_json::json!({
+        "items": [note("3a1b2c3d-4e5f-4a6b-9c7d-000000000001", "content")]
+    }));
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [], "metadata_only": true }));
+    let it = find_item(&resp["retrieved_items"], "3a1b2c3d-4e5f-4a6b-9c7d-000000000001");
+    assert_eq!(it["content_type"], "Note");
+    assert!(it["updated_at"].is_string());
+    assert!(it["content"].is_null());
+    assert!(it["enc_item_key"].is_null());
+
+    // Conflicts still carry everything needed to merge
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [note("3a1b2c3d-4e5f-4a6b-9c7d-000000000001", "changed")],
+        "metadata_only": true
+    }));
+    let conflicts = resp["conflicts"].as_array().unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0]["server_item"]["content"], "content");
+    assert_eq!(conflicts[0]["server_item"]["enc_item_key"], "key");
+}
+
+#[test]
+fn should_reject_items_from_future() {
+    let token = register_user("test36@example.com");
+    let item = |uuid: &str, created_at: &str|
+        with_fields(note(uuid, "content"), serde_json::json!({ "created_at": created_at }));
+    let (status, val) = sync_items_with(&CLIENT, &token, serde_json::json!({
+        "items": [
+            item("4a1b2c3d-4e5f-4a6b-9c7d-000000000001", "2020-02-22T00:00:00.000Z"),
+            item("4a1b2c3d-4e5f-4a6b-9c7d-000000000002", "2999-01-01T00:00:00.000Z")
+        ]
+    }));
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(val["errors"].as_array().unwrap().len(), 1);
+
+    // Nothing was saved
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+
+    // A few minutes are fine
+    let soon = (chrono::Utc::now() + chrono::Duration::minutes(5)).to_rfc3339();
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [item("4a1b2c3d-4e5f-4a6b-9c7d-000000000003", &soon)]
+    }));
+    assert_eq!(resp["saved_items"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn should_return_aliased_content_types() {
+    let token = register_user("test37@example.com");
+    sync_items(&token, serde_json::json!({
+        "items": [with_fields(note("5a1b2c3d-4e5f-4a6b-9c7d-000000000001", "content"),
+            serde_json::json!({ "content_type": "OldNote" }))]
+    }));
+
+    let mut config = crate::config::Config::from_env();
+    config.content_type_aliases.insert("OldNote".into(), "SN|Note".into());
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let (status, val) = sync_items_with(&client, &token, serde_json::json!({ "items": [] }));
+    assert_eq!(status, Status::Ok);
+    let it = find_item(&val["retrieved_items"], "5a1b2c3d-4e5f-4a6b-9c7d-000000000001");
+    assert_eq!(it["content_type"], "SN|Note");
+
+    // No-op when unconfigured
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    let it = find_item(&resp["retrieved_items"], "5a1b2c3d-4e5f-4a6b-9c7d-000000000001");
+    assert_eq!(it["content_type"], "OldNote");
+}
+
+#[test]
+fn should_list_item_metadata_for_admin() {
+    let token = register_user("test38@example.com");
+    let mut resp = CLIENT.get("/auth/me")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let user_uuid = val["uuid"].as_str().unwrap().to_string();
+    sync_items(&token, serde_json::json!({
+        "items": [note("6a1b2c3d-4e5f-4a6b-9c7d-000000000001", "secret")]
+    }));
+
+    let list_items = |token: &str| CLIENT
+        .get(format!("/admin/users/{}/items?limit=1", user_uuid))
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    let mut resp = list_items(&ADMIN_TOKEN);
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let items = val["items"].as_array().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0]["uuid"], "6a1b2c3d-4e5f-4a6b-9c7d-000000000001");
+    assert_eq!(items[0]["content_type"], "Note");
+    assert_eq!(items[0]["size"], 6);
+    assert!(items[0].get("content").is_none());
+    assert!(items[0].get("enc_item_key").is_none());
+    assert!(val["cursor"].is_string());
+
+    assert_eq!(list_items(&token).status(), Status::Forbidden);
+    let resp = CLIENT.get("/admin/users/nope/items")
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
+#[test]
+fn should_pretty_print_json_on_request() {
+    let token = register_user("test39@example.com");
+    let sync = |client: &Client, uri: &str| {
+        let mut resp = client.post(uri.to_string())
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(serde_json::json!({ "items": [] }).to_string())
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.content_type(), Some(ContentType::JSON));
+        resp.body_string().unwrap()
+    };
+    assert!(!sync(&CLIENT, "/items/sync").contains('\n'));
+    assert!(sync(&CLIENT, "/items/sync?pretty").contains("\n  \"retrieved_items\""));
+    for uri in &["/items/search?content_type=Note&pretty", "/items/export?limit=10&pretty"] {
+        let mut resp = CLIENT.get(uri.to_string())
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        assert!(resp.body_string().unwrap().contains("\n  \"items\""));
+    }
+
+    let mut config = crate::config::Config::from_env();
+    config.pretty_json = true;
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    assert!(sync(&client, "/items/sync").contains("\n  \"retrieved_items\""));
+}
+
+#[test]
+fn should_throttle_rapid_item_updates() {
+    let token = register_user("test40@example.com");
+    let mut config = crate::config::Config::from_env();
+    config.min_item_update_interval_secs = Some(1);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let sync_token = std::cell::RefCell::new(serde_json::Value::Null);
+    let sync = |content: &str, deleted: bool| {
+        let item = with_fields(note("7a1b2c3d-4e5f-4a6b-9c7d-000000000001", content),
+            serde_json::json!({ "deleted": deleted }));
+        let (status, val) = sync_items_with(&client, &token, serde_json::json!({
+            "items": [item],
+            "sync_token": *sync_token.borrow()
+        }));
+        assert_eq!(status, Status::Ok);
+        sync_token.replace(val["sync_token"].clone());
+        val
+    };
+
+    assert!(sync("v1", false)["conflicts"].as_array().unwrap().is_empty());
+    let resp = sync("v2", false);
+    let conflicts = resp["conflicts"].as_array().unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0]["type"], "update_throttled");
+    assert_eq!(conflicts[0]["unsaved_item"]["content"], "v2");
+    assert!(resp["saved_items"].as_array().unwrap().is_empty());
+
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let resp = sync("v3", false);
+    assert!(resp["conflicts"].as_array().unwrap().is_empty());
+    assert_eq!(resp["saved_items"].as_array().unwrap().len(), 1);
+
+    // Deletions are never throttled
+    let resp = sync("v3", true);
+    assert!(resp["conflicts"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_rotate_password_and_sessions() {
+    let token = register_user("test41@example.com");
+    let other_token = sign_in("test41@example.com", "testpw");
+    let rotate = |token: &str, current_password: &str| {
+        let mut resp = CLIENT.post("/auth/rotate")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(serde_json::json!({
+                "current_password": current_password,
+                "password": "testpw1",
+                "pw_cost": 110000,
+                "pw_nonce": "new_nonce",
+                "version": "004"
+            }).to_string())
+            .dispatch();
+        (resp.status(), resp.body_string())
+    };
+    assert_eq!(rotate(&token, "wrong").0, Status::Forbidden);
+
+    let (status, body) = rotate(&token, "testpw");
+    assert_eq!(status, Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&body.unwrap()).unwrap();
+    let new_token = val["token"].as_str().unwrap();
+
+    // Every old session is gone, the new one works
+    for t in &[&token, &other_token] {
+        let resp = CLIENT.get("/auth/ping")
+            .header(Header::new("Authorization", format!("Bearer {}", t)))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Unauthorized);
+    }
+    let resp = CLIENT.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", new_token)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+
+    let mut resp = CLIENT.get("/auth/params?email=test41@example.com").dispatch();
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val, serde_json::json!({
+        "pw_cost": 110000,
+        "pw_nonce": "new_nonce",
+        "version": "004"
+    }));
+
+    sign_in("test41@example.com", "testpw1");
+}
+
+#[test]
+fn should_purge_deleted_accounts_after_retention() {
+    let delete_account = |token: &str| CLIENT.post("/auth/delete")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "password": "testpw" }).to_string())
+        .dispatch()
+        .status();
+    let token = register_user("test42@example.com");
+    sync_items(&token, serde_json::json!({
+        "items": [note("8a1b2c3d-4e5f-4a6b-9c7d-000000000001", "content")]
+    }));
+    assert_eq!(delete_account(&token), Status::NoContent);
+    let resp = CLIENT.post("/auth/sign_in")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({ "email": "test42@example.com", "password": "testpw" }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    let lock = CLIENT.rocket().state::<crate::lock::UserLock>().unwrap();
+    let db = get_test_db();
+    crate::tasks::purge_deleted_accounts(&db.0, lock, 1);
+    assert!(crate::user::User::find_user_by_email(&db.0, "test42@example.com").is_ok());
+    crate::tasks::purge_deleted_accounts(&db.0, lock, 0);
+    assert!(crate::user::User::find_user_by_email(&db.0, "test42@example.com").is_err());
+    std::mem::drop(db);
+
+    // Admins can restore accounts within the retention period
+    let token = register_user("test42b@example.com");
+    assert_eq!(delete_account(&token), Status::NoContent);
+    let resp = CLIENT.post("/admin/restore")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .body(serde_json::json!({ "email": "test42b@example.com" }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::NoContent);
+    let db = get_test_db();
+    crate::tasks::purge_deleted_accounts(&db.0, lock, 0);
+    std::mem::drop(db);
+    sign_in("test42b@example.com", "testpw");
+
+    // ...and so does lifting the suspension
+    let token = register_user("test42c@example.com");
+    assert_eq!(delete_account(&token), Status::NoContent);
+    let resp = CLIENT.post("/admin/suspend")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .body(serde_json::json!({ "email": "test42c@example.com", "suspended": false }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::NoContent);
+    let db = get_test_db();
+    crate::tasks::purge_deleted_accounts(&db.0, lock, 0);
+    std::mem::drop(db);
+    sign_in("test42c@example.com", "testpw");
+}
+
+#[test]
+fn should_save_valid_items_next_to_oversized_ones() {
+    let token = register_user("test43@example.com");
+    let mut config = crate::config::Config::from_env();
+    config.max_item_size_bytes = Some(16);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let (status, val) = sync_items_with(&client, &token, serde_json::json!({
+        "items": [
+            note("9a1b2c3d-4e5f-4a6b-9c7d-000000000001", "small"),
+            note("9a1b2c3d-4e5f-4a6b-9c7d-000000000002", &"x".repeat(17))
+        ]
+    }));
+    assert_eq!(status, Status::Ok);
+    let saved = val["saved_items"].as_array().unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0]["uuid"], "9a1b2c3d-4e5f-4a6b-9c7d-000000000001");
+    let unsaved = val["unsaved"].as_array().unwrap();
+    assert_eq!(unsaved.len(), 1);
+    assert_eq!(unsaved[0]["uuid"], "9a1b2c3d-4e5f-4a6b-9c7d-000000000002");
+    assert!(unsaved[0]["error"].is_string());
+
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+    assert!(resp["unsaved"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_send_notices_until_acknowledged() {
+    let token = register_user("test44@example.com");
+    let mut resp = CLIENT.post("/admin/notices")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .body(serde_json::json!({ "message": "Maintenance tonight" }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let id = val["id"].as_i64().unwrap();
+
+    // Other tests may create notices, too
+    let has_notice = || {
+        let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+        resp.get("notices")
+            .and_then(|n| n.as_array())
+            .map(|n| n.iter().any(|n| n["id"] == id && n["message"] == "Maintenance tonight"))
+            .unwrap_or(false)
+    };
+    assert!(has_notice());
+    assert!(has_notice());
+
+    let ack = |id: i64| CLIENT.post(format!("/notices/{}/ack", id))
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch()
+        .status();
+    assert_eq!(ack(id), Status::NoContent);
+    assert!(!has_notice());
+    assert_eq!(ack(-1), Status::NotFound);
+
+    let resp = CLIENT.post("/admin/notices")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "message": "Nope" }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Forbidden);
+}
+
+#[test]
+fn should_limit_concurrent_requests_per_user() {
+    let token = register_user("test45@example.com");
+    let mut config = crate::config::Config::from_env();
+    config.max_concurrent_requests_per_user = Some(1);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let ping = || client.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+
+    // A local response keeps its request, and thus the permit, alive
+    let first = ping();
+    assert_eq!(first.status(), Status::Ok);
+    let mut second = ping();
+    assert_eq!(second.status(), Status::TooManyRequests);
+    let val = serde_json::from_str::<serde_json::Value>(&second.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "too_many_requests");
+
+    // Other users are not affected
+    let other = register_user("test45b@example.com");
+    let resp = client.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", other)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+
+    std::mem::drop(first);
+    std::mem::drop(second);
+    assert_eq!(ping().status(), Status::Ok);
+}
+
+#[test]
+fn should_number_items_per_user() {
+    register_user("test46@example.com");
+    register_user("test47@example.com");
+    let db = get_test_db();
+    let a = crate::user::User::find_user_by_email(&db.0, "test46@example.com").unwrap();
+    let b = crate::user::User::find_user_by_email(&db.0, "test47@example.com").unwrap();
+    let save = |u, it: &crate::item::SyncItem| crate::item::SyncItem::items_insert(&db.0, u, it, None).unwrap();
+
+    let a1 = crate::item::SyncItem::new_note("a1");
+    let a2 = crate::item::SyncItem::new_note("a2");
+    let b1 = crate::item::SyncItem::new_note("b1");
+    let b2 = crate::item::SyncItem::new_note("b2");
+    // Saves of one user do not show up in the sequence of another
+    assert_eq!(save(&a, &a1), 1);
+    assert_eq!(save(&b, &b1), 1);
+    assert_eq!(save(&a, &a2), 2);
+    // Updating the latest item still moves it to a new number
+    assert_eq!(save(&a, &a2), 3);
+    assert_eq!(save(&a, &a1), 4);
+    assert_eq!(save(&b, &b2), 2);
+
+    let seqs = |u| crate::item::SyncItem::items_of_user(&db.0, u, None, None, None, None).unwrap()
+        .into_iter()
+        .map(|it| (it.uuid, it.user_seq))
+        .collect::<Vec<_>>();
+    assert_eq!(seqs(&a), vec![(a2.uuid.clone(), 3), (a1.uuid.clone(), 4)]);
+    assert_eq!(seqs(&b), vec![(b1.uuid.clone(), 1), (b2.uuid.clone(), 2)]);
+}
+
+#[test]
+fn should_accept_sync_tokens_from_before_user_seq() {
+    let token = register_user("test48@example.com");
+    let item = |n| note(&format!("aa1b2c3d-4e5f-4a6b-9c7d-00000000000{}", n), "content");
+    sync_items(&token, serde_json::json!({ "items": [item(1)] }));
+    sync_items(&token, serde_json::json!({ "items": [item(2)] }));
+
+    // Tokens used to carry the global ID of the latest item seen
+    let db = get_test_db();
+    let u = crate::user::User::find_user_by_email(&db.0, "test48@example.com").unwrap();
+    let first = crate::item::SyncItem::find_item_by_uuid(&db.0, &u,
+        "aa1b2c3d-4e5f-4a6b-9c7d-000000000001").unwrap();
+    std::mem::drop(db);
+
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "sync_token": crate::sync_tokens::max_id_to_token(first.id)
+    }));
+    let retrieved = resp["retrieved_items"].as_array().unwrap();
+    assert_eq!(retrieved.len(), 1);
+    assert_eq!(retrieved[0]["uuid"], "aa1b2c3d-4e5f-4a6b-9c7d-000000000002");
+
+    // ...and the client moves on with a new one
+    let resp = sync_items(&token, serde_json::json!({
+        "items": [],
+        "sync_token": resp["sync_token"]
+    }));
+    assert!(resp["retrieved_items"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn should_report_all_invalid_fields_at_once() {
+    let mut resp = CLIENT.post("/auth")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({
+            "email": "not an email",
+            "password": "pw",
+            "pw_cost": 0,
+            "pw_nonce": "",
+            "version": "999"
+        }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::BadRequest);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    assert_eq!(val["code"], "bad_request");
+    assert_eq!(val["errors"].as_array().unwrap().len(), 5);
+    let fields = val["field_errors"].as_array().unwrap().iter()
+        .map(|e| (e["field"].as_str().unwrap(), e["code"].as_str().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(fields, vec![
+        ("email", "invalid_email"),
+        ("password", "password_too_short"),
+        ("pw_cost", "pw_cost_too_low"),
+        ("pw_nonce", "pw_nonce_missing"),
+        ("version", "unsupported_version")
+    ]);
+
+    // Nothing was registered
+    let db = get_test_db();
+    assert!(crate::user::User::find_user_by_email(&db.0, "not an email").is_err());
+}
+
+#[test]
+fn should_store_large_contents_outside_items() {
+    use crate::schema::{items, large_contents};
+    use diesel::prelude::*;
+
+    let token = register_user("test49@example.com");
+    let mut config = crate::config::Config::from_env();
+    config.inline_content_max_bytes = Some(16);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let sync = |params: serde_json::Value| {
+        let (status, val) = sync_items_with(&client, &token, params);
+        assert_eq!(status, Status::Ok);
+        val
+    };
+    let item = |n, content: &str| note(&format!("ab1b2c3d-4e5f-4a6b-9c7d-00000000000{}", n), content);
+    let large = "x".repeat(100);
+    sync(serde_json::json!({ "items": [item(1, &large), item(2, "small")] }));
+
+    // Only the large one went to the sidecar
+    let stored = |n| {
+        let db = get_test_db();
+        let uuid = format!("ab1b2c3d-4e5f-4a6b-9c7d-00000000000{}", n);
+        let inline = items::table.filter(items::uuid.eq(&uuid))
+            .select(items::content)
+            .first::<Option<String>>(&db.0)
+            .unwrap();
+        let sidecar = large_contents::table.filter(large_contents::uuid.eq(&uuid))
+            .select(large_contents::content)
+            .first::<String>(&db.0)
+            .optional()
+            .unwrap();
+        (inline, sidecar)
+    };
+    assert_eq!(stored(1), (None, Some(large.clone())));
+    assert_eq!(stored(2), (Some("small".to_string()), None));
+
+    // Reads put it back together
+    let resp = sync(serde_json::json!({ "items": [] }));
+    assert_eq!(find_item(&resp["retrieved_items"], "ab1b2c3d-4e5f-4a6b-9c7d-000000000001")["content"], large);
+    assert_eq!(find_item(&resp["retrieved_items"], "ab1b2c3d-4e5f-4a6b-9c7d-000000000002")["content"], "small");
+
+    // Shrinking an item moves it back inline
+    sync(serde_json::json!({ "items": [item(1, "small again")], "sync_token": resp["sync_token"] }));
+    assert_eq!(stored(1), (Some("small again".to_string()), None));
+
+    // Content left behind without its item does not get in the way
+    {
+        let db = get_test_db();
+        let u = crate::user::User::find_user_by_email(&db.0, "test49@example.com").unwrap();
+        diesel::insert_into(large_contents::table)
+            .values((
+                large_contents::owner.eq(u.id),
+                large_contents::uuid.eq("ab1b2c3d-4e5f-4a6b-9c7d-000000000003"),
+                large_contents::content.eq("stale")
+            ))
+            .execute(&db.0)
+            .unwrap();
+    }
+    let resp = sync(serde_json::json!({ "items": [item(3, &large)] }));
+    assert!(resp["unsaved"].as_array().unwrap().is_empty());
+    assert_eq!(stored(3), (None, Some(large.clone())));
+}
+
+#[test]
+fn should_report_lock_contention() {
+    let mut config = crate::config::Config::from_env();
+    // Our own UserLock, so that other tests do not count
+    let client = Client::new(crate::build_rocket_with_config(config.clone()))
+        .expect("valid rocket instance");
+    let locks = || {
+        let mut resp = client.get("/admin/locks")
+            .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap()
+    };
+    assert_eq!(locks()["user_locks_held"], 0);
+    assert!(locks()["db_write_waiters"].is_u64());
+
+    let lock = client.rocket().state::<crate::lock::UserLock>().unwrap();
+    let mutex = lock.get_mutex(-1);
+    let held = lock.lock(&mutex);
+    assert_eq!(locks()["user_locks_held"], 1);
+    std::mem::drop(held);
+    assert_eq!(locks()["user_locks_held"], 0);
+
+    // Nobody but admins
+    config.admin_emails = vec![];
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let resp = client.get("/admin/locks")
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Forbidden);
+}
+
+#[test]
+fn should_count_db_write_waiters() {
+    use std::sync::atomic::Ordering;
+
+    // Endpoints cannot be called in the meantime, as they would
+    // have to wait for the same lock, so look at the counter directly
+    let before = crate::DB_WRITE_WAITERS.load(Ordering::SeqCst);
+    let guard = crate::DB_LOCK.write().unwrap();
+    let waiter = std::thread::spawn(|| {
+        let _lock = crate::lock_db_for_write();
+    });
+    let mut waiting = false;
+    for _ in 0..100 {
+        if crate::DB_WRITE_WAITERS.load(Ordering::SeqCst) > before {
+            waiting = true;
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    assert!(waiting);
+}
+
+#[test]
+fn should_answer_sync_retries_with_the_same_response() {
+    let token = register_user("test50@example.com");
+    let mut config = crate::config::Config::from_env();
+    config.sync_dedup_window_secs = Some(60);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let sync = |params: &serde_json::Value| {
+        let (status, val) = sync_items_with(&client, &token, params.clone());
+        assert_eq!(status, Status::Ok);
+        val
+    };
+    let params = serde_json::json!({
+        "items": [note("ac1b2c3d-4e5f-4a6b-9c7d-000000000001", "content")]
+    });
+    let user_seq = || {
+        let db = get_test_db();
+        let u = crate::user::User::find_user_by_email(&db.0, "test50@example.com").unwrap();
+        crate::item::SyncItem::find_item_by_uuid(&db.0, &u, "ac1b2c3d-4e5f-4a6b-9c7d-000000000001")
+            .unwrap()
+            .user_seq
+    };
+
+    let first = sync(&params);
+    let seq = user_seq();
+    // Tokens are encrypted with a random nonce, so an equal one was not made anew
+    let retry = sync(&params);
+    assert_eq!(retry, first);
+    assert_eq!(user_seq(), seq);
+
+    // Anything else is synced as usual
+    let other = sync(&serde_json::json!({
+        "items": params["items"],
+        "sync_token": first["sync_token"]
+    }));
+    assert_ne!(other["sync_token"], first["sync_token"]);
+    assert!(user_seq() > seq);
+
+    // Nor is a retry after something else changed what the sync would return
+    let deletion = serde_json::json!({
+        "items": [with_fields(note("ac1b2c3d-4e5f-4a6b-9c7d-000000000002", "content"),
+            serde_json::json!({ "deleted": true }))]
+    });
+    let first = sync(&deletion);
+    let resp = client.post("/items/purge")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "uuids": ["ac1b2c3d-4e5f-4a6b-9c7d-000000000002"] }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::NoContent);
+    let retry = sync(&deletion);
+    assert_ne!(retry["sync_token"], first["sync_token"]);
+
+    let mut resp = client.post("/admin/notices")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", *ADMIN_TOKEN)))
+        .body(serde_json::json!({ "message": "Retry me" }).to_string())
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let val = serde_json::from_str::<serde_json::Value>(&resp.body_string().unwrap()).unwrap();
+    let retry = sync(&deletion);
+    assert!(retry["notices"].as_array().unwrap().iter().any(|n| n["id"] == val["id"]));
+}
+
+#[test]
+fn should_purge_tombstones_only() {
+    let token = register_user("test51@example.com");
+    let item = |n, deleted: bool| with_fields(
+        note(&format!("ad1b2c3d-4e5f-4a6b-9c7d-00000000000{}", n), "content"),
+        serde_json::json!({ "deleted": deleted }));
+    let resp = sync_items(&token, serde_json::json!({ "items": [item(1, false), item(2, false)] }));
+    sync_items(&token, serde_json::json!({ "items": [item(1, true)], "sync_token": resp["sync_token"] }));
+
+    let purge = |uuids: serde_json::Value| CLIENT.post("/items/purge")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "uuids": uuids }).to_string())
+        .dispatch()
+        .status();
+    let existing = || {
+        let db = get_test_db();
+        let u = crate::user::User::find_user_by_email(&db.0, "test51@example.com").unwrap();
+        let mut existing = crate::item::SyncItem::find_existing_uuids(&db.0, &u, &[
+            "ad1b2c3d-4e5f-4a6b-9c7d-000000000001".to_string(),
+            "ad1b2c3d-4e5f-4a6b-9c7d-000000000002".to_string()
+        ]).unwrap();
+        existing.sort();
+        existing
+    };
+
+    // Items that are not deleted are refused, along with the rest of the request
+    assert_eq!(purge(serde_json::json!([
+        "ad1b2c3d-4e5f-4a6b-9c7d-000000000001",
+        "ad1b2c3d-4e5f-4a6b-9c7d-000000000002"
+    ])), Status::BadRequest);
+    assert_eq!(existing().len(), 2);
+
+    assert_eq!(purge(serde_json::json!(["ad1b2c3d-4e5f-4a6b-9c7d-000000000001"])), Status::NoContent);
+    assert_eq!(existing(), vec![("ad1b2c3d-4e5f-4a6b-9c7d-000000000002".to_string(), false)]);
+
+    // Gone means gone
+    assert_eq!(purge(serde_json::json!(["ad1b2c3d-4e5f-4a6b-9c7d-000000000001"])), Status::BadRequest);
+    let resp = sync_items(&token, serde_json::json!({ "items": [] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn should_keep_sync_token_valid_after_purging_latest_item() {
+    let token = register_user("test53@example.com");
+    let item = |n, deleted: bool| with_fields(
+        note(&format!("ae1b2c3d-4e5f-4a6b-9c7d-00000000000{}", n), "content"),
+        serde_json::json!({ "deleted": deleted }));
+    let resp = sync_items(&token, serde_json::json!({ "items": [item(1, false), item(2, false)] }));
+    let resp = sync_items(&token, serde_json::json!({ "items": [item(2, true)], "sync_token": resp["sync_token"] }));
+    let status = CLIENT.post("/items/purge")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "uuids": ["ae1b2c3d-4e5f-4a6b-9c7d-000000000002"] }).to_string())
+        .dispatch()
+        .status();
+    assert_eq!(status, Status::NoContent);
+
+    // The token covers the purged tombstone, which is no reason for a full sync
+    let resp = sync_items(&token, serde_json::json!({ "items": [], "sync_token": resp["sync_token"] }));
+    assert_eq!(resp["retrieved_items"].as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn should_refresh_tokens_close_to_expiry() {
+    use diesel::prelude::*;
+    use crate::schema::tokens::dsl::*;
+
+    let mut config = crate::config::Config::from_env();
+    config.token_lifetime_days = Some(30);
+    config.token_refresh_window_hours = Some(24);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let ping = |token: &str| client.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+    let set_issued_at = |token: &str, hours_ago: i64| {
+        let db = get_test_db();
+        diesel::update(tokens.find(token))
+            .set(timestamp.eq(chrono::Utc::now().naive_utc() - chrono::Duration::hours(hours_ago)))
+            .execute(&db.0)
+            .unwrap();
+    };
+
+    let fresh = register_user("test52@example.com");
+    let resp = ping(&fresh);
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(resp.headers().get_one("X-Refreshed-Token").is_none());
+
+    let old = sign_in("test52@example.com", "testpw");
+    // Expires in 12 hours
+    set_issued_at(&old, 30 * 24 - 12);
+    let resp = ping(&old);
+    assert_eq!(resp.status(), Status::Ok);
+    let refreshed = resp.headers().get_one("X-Refreshed-Token").unwrap().to_string();
+    assert_ne!(refreshed, old);
+    // The same new token until the client adopts it
+    let resp = ping(&old);
+    assert_eq!(resp.headers().get_one("X-Refreshed-Token"), Some(&refreshed[..]));
+    let resp = ping(&refreshed);
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(resp.headers().get_one("X-Refreshed-Token").is_none());
+
+    set_issued_at(&old, 31 * 24);
+    assert_eq!(ping(&old).status(), Status::Unauthorized);
+}
+
+#[test]
+fn should_not_evict_refreshed_tokens() {
+    use diesel::prelude::*;
+    use crate::schema::tokens::dsl::*;
+
+    let mut config = crate::config::Config::from_env();
+    config.token_lifetime_days = Some(30);
+    config.token_refresh_window_hours = Some(24);
+    config.max_sessions_per_user = Some(1);
+    let client = Client::new(crate::build_rocket_with_config(config))
+        .expect("valid rocket instance");
+    let ping = |token: &str| client.get("/auth/ping")
+        .header(Header::new("Authorization", format!("Bearer {}", token)))
+        .dispatch();
+
+    let old = register_user("test54@example.com");
+    let db = get_test_db();
+    diesel::update(tokens.find(&old))
+        .set(timestamp.eq(chrono::Utc::now().naive_utc() - chrono::Duration::hours(30 * 24 - 12)))
+        .execute(&db.0)
+        .unwrap();
+    std::mem::drop(db);
+
+    let resp = ping(&old);
+    let refreshed = resp.headers().get_one("X-Refreshed-Token").unwrap().to_string();
+    // The client may not have picked up the new token yet
+    assert_eq!(ping(&old).status(), Status::Ok);
+    assert_eq!(ping(&refreshed).status(), Status::Ok);
 }
\ No newline at end of file
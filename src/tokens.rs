@@ -1,11 +1,17 @@
 use crate::schema::tokens;
 use crate::schema::tokens::dsl::*;
 use crate::{SqliteLike, lock_db_write, lock_db_read};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
-use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use diesel::result::{Error, DatabaseErrorKind};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLockReadGuard, RwLockWriteGuard};
 use uuid::Uuid;
 
+// How many times we try to generate a fresh token id
+// before giving up on creating a token
+const MAX_TOKEN_ATTEMPTS: usize = 5;
+
 #[derive(Queryable, Insertable)]
 #[table_name = "tokens"]
 pub struct Token {
@@ -15,8 +21,8 @@ pub struct Token {
 }
 
 impl Token {
-    // Return user id if any
-    pub fn find_token_by_id(db: &impl SqliteLike, tid: &str) -> Option<i32> {
+    // Return user id and when the token was issued, if any
+    pub fn find_token_by_id(db: &impl SqliteLike, tid: &str) -> Option<(i32, Option<NaiveDateTime>)> {
         (lock_db_read!() as Result<RwLockReadGuard<()>, String>).ok()
             .and_then(|_| {
                 tokens.filter(id.eq(tid))
@@ -24,7 +30,8 @@ impl Token {
                     .ok()
                     .and_then(|mut v| {
                         if !v.is_empty() {
-                            Some(v.remove(0).uid)
+                            let t = v.remove(0);
+                            Some((t.uid, t.timestamp))
                         } else {
                             None
                         }
@@ -33,19 +40,102 @@ impl Token {
     }
 
     // Create a new token for a user
-    pub fn create_token(db: &impl SqliteLike, user: i32) -> Option<String> {
-        let tid = Uuid::new_v4().to_hyphenated().to_string();
-        (lock_db_write!() as Result<RwLockWriteGuard<()>, String>).ok()
-            .and_then(|_| {
-                diesel::insert_into(tokens::table)
-                    .values(Token {
-                        id: tid.clone(),
-                        uid: user,
-                        timestamp: None // There's default value from SQLite
-                    })
-                    .execute(db)
-                    .ok()
-                    .map(|_| tid)
-            })
+    // If the user would end up with more than `max_sessions` tokens,
+    // the oldest ones are revoked
+    pub fn create_token(db: &impl SqliteLike, user: i32, max_sessions: Option<i64>) -> Result<String, String> {
+        Self::create_token_with(db, user, max_sessions, || Uuid::new_v4().to_hyphenated().to_string())
+    }
+
+    // Create a new token for a user, with ids produced by `gen`
+    // A UUIDv4 collision is astronomically unlikely, but if the insertion
+    // ever hits an existing id, we simply ask for another one
+    // (up to MAX_TOKEN_ATTEMPTS times) instead of failing the sign-in
+    pub fn create_token_with(
+        db: &impl SqliteLike, user: i32, max_sessions: Option<i64>,
+        mut gen: impl FnMut() -> String
+    ) -> Result<String, String> {
+        let _lock = (lock_db_write!() as Result<RwLockWriteGuard<()>, String>)?;
+        for _ in 0..MAX_TOKEN_ATTEMPTS {
+            let tid = gen();
+            let res = diesel::insert_into(tokens::table)
+                .values(Token {
+                    id: tid.clone(),
+                    uid: user,
+                    // Not relying on the default from SQLite, because it
+                    // only has second precision and we order sessions by this
+                    timestamp: Some(Utc::now().naive_utc())
+                })
+                .execute(db);
+            match res {
+                Ok(_) => {
+                    if let Some(max_sessions) = max_sessions {
+                        Self::evict_oldest(db, user, max_sessions)
+                            .map_err(|_| "Database error")?;
+                    }
+                    return Ok(tid);
+                },
+                Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => continue,
+                Err(_) => return Err("Database error".into())
+            }
+        }
+
+        Err("Could not generate a unique token".into())
+    }
+
+    // Revoke every token of a user
+    // The caller should hold the database write lock
+    pub fn revoke_all(db: &impl SqliteLike, user: i32) -> QueryResult<()> {
+        diesel::delete(tokens.filter(uid.eq(user)))
+            .execute(db)
+            .map(|_| ())
+    }
+
+    // Revoke the oldest tokens of a user until at most `max_sessions` are left
+    // The caller should hold the database write lock
+    fn evict_oldest(db: &impl SqliteLike, user: i32, max_sessions: i64) -> QueryResult<()> {
+        let count = tokens.filter(uid.eq(user))
+            .count()
+            .get_result::<i64>(db)?;
+        if count <= max_sessions {
+            return Ok(());
+        }
+
+        // Tokens without a timestamp sort first, i.e. are considered the oldest
+        let oldest = tokens.filter(uid.eq(user))
+            .order(timestamp.asc())
+            .limit(count - max_sessions)
+            .select(id)
+            .load::<String>(db)?;
+        diesel::delete(tokens.filter(id.eq_any(oldest)))
+            .execute(db)
+            .map(|_| ())
+    }
+}
+
+// Tokens handed out to replace ones about to expire, by the id of the old one
+// (see TOKEN_REFRESH_WINDOW_HOURS). They are remembered until the old ones
+// expire, so that a client making more requests before it adopts the new
+// token does not get yet another one every time.
+pub struct RefreshedTokens(Mutex<HashMap<String, (String, NaiveDateTime)>>);
+
+impl RefreshedTokens {
+    pub fn new() -> RefreshedTokens {
+        RefreshedTokens(Mutex::new(HashMap::new()))
+    }
+
+    pub fn get_or_create(
+        &self, old: &str, old_expires_at: NaiveDateTime,
+        create: impl FnOnce() -> Result<String, String>
+    ) -> Result<String, String> {
+        let mut refreshed = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Utc::now().naive_utc();
+        refreshed.retain(|_, (_, expires_at)| *expires_at > now);
+        if let Some((new, _)) = refreshed.get(old) {
+            return Ok(new.clone());
+        }
+
+        let new = create()?;
+        refreshed.insert(old.to_string(), (new.clone(), old_expires_at));
+        Ok(new)
     }
 }
\ No newline at end of file
@@ -2,10 +2,14 @@ use crate::schema::users;
 use crate::schema::users::dsl::*;
 use crate::{SqliteLike, lock_db_write, lock_db_read};
 use ::uuid::Uuid;
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
+use crate::config::Config;
+use crate::ratelimit::UserConcurrency;
+use rocket::State;
 use rocket::request;
 use rocket::http::Status;
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 
 #[derive(Debug)]
 pub struct UserOpError(pub String);
@@ -62,7 +66,13 @@ struct UserQuery {
     pub password: String,
     pub pw_cost: i32,
     pub pw_nonce: String,
-    pub version: String
+    pub version: String,
+    pub suspended: bool,
+    pub last_sync: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    // Only ever read (and advanced) while saving items,
+    // so it is not carried over into User (see SyncItem::items_insert)
+    pub _last_item_seq: i64
 }
 
 impl Into<User> for UserQuery {
@@ -76,7 +86,10 @@ impl Into<User> for UserQuery {
             password: Password(self.password),
             pw_cost: self.pw_cost,
             pw_nonce: self.pw_nonce,
-            version: self.version
+            version: self.version,
+            suspended: self.suspended,
+            last_sync: self.last_sync,
+            deleted_at: self.deleted_at
         }
     }
 }
@@ -89,9 +102,21 @@ pub struct User {
     pub password: Password,
     pub pw_cost: i32,
     pub pw_nonce: String,
-    pub version: String
+    pub version: String,
+    // Suspended users keep their data but can neither sign in nor sync
+    pub suspended: bool,
+    // When the last successful sync finished (UTC)
+    pub last_sync: Option<NaiveDateTime>,
+    // When the user deleted the account (UTC); it is kept suspended
+    // until purged after ACCOUNT_RETENTION_DAYS
+    pub deleted_at: Option<NaiveDateTime>
 }
 
+// Error code sent to suspended users
+pub const ACCOUNT_SUSPENDED: &str = "account_suspended";
+
+const DATABASE_UNAVAILABLE: &str = "Database unavailable";
+
 #[derive(Deserialize)]
 pub struct NewUser {
     pub email: String,
@@ -101,6 +126,77 @@ pub struct NewUser {
     pub version: String
 }
 
+// Key derivation parameters to update along with the password
+// Fields left as None are kept as they are
+#[derive(AsChangeset, Default)]
+#[table_name="users"]
+pub struct KeyParams {
+    pub pw_cost: Option<i32>,
+    pub pw_nonce: Option<String>,
+    pub version: Option<String>
+}
+
+// Protocol versions of Standard Notes
+const SUPPORTED_VERSIONS: [&str; 4] = ["001", "002", "003", "004"];
+
+// Clients send a key derived from the password rather than the password
+// itself, so this only catches obviously broken ones
+pub const MIN_PASSWORD_LENGTH: usize = 6;
+
+// A problem with one field of a request
+// Requests are checked as a whole, so that clients can show all of them at once
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    // Machine-readable, e.g. `invalid_email`
+    pub code: &'static str,
+    pub error: String
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, error: impl Into<String>) -> FieldError {
+        FieldError {
+            field,
+            code,
+            error: error.into()
+        }
+    }
+}
+
+pub fn validate_password(passwd: &str) -> Result<(), FieldError> {
+    if passwd.len() < MIN_PASSWORD_LENGTH {
+        Err(FieldError::new("password", "password_too_short",
+            format!("password must be at least {} characters long", MIN_PASSWORD_LENGTH)))
+    } else {
+        Ok(())
+    }
+}
+
+impl KeyParams {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = vec![];
+        if self.pw_cost.map(|c| c <= 0).unwrap_or(false) {
+            errors.push(FieldError::new("pw_cost", "pw_cost_too_low", "pw_cost must be positive"));
+        }
+
+        if self.pw_nonce.as_ref().map(|n| n.is_empty()).unwrap_or(false) {
+            errors.push(FieldError::new("pw_nonce", "pw_nonce_missing", "pw_nonce must not be empty"));
+        }
+
+        if let Some(ref v) = self.version {
+            if !SUPPORTED_VERSIONS.contains(&v.as_str()) {
+                errors.push(FieldError::new("version", "unsupported_version", "Unsupported version"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 #[derive(Insertable)]
 #[table_name="users"]
 struct NewUserInsert {
@@ -161,25 +257,173 @@ impl User {
         }
     }
 
-    pub fn find_user_by_token(db: &impl SqliteLike, token: &str) -> Result<User, UserOpError> {
-        crate::tokens::Token::find_token_by_id(db, token)
-            .ok_or("Invalid token".into())
-            .and_then(|uid| Self::find_user_by_id(db, uid))
+    pub fn find_user_by_uuid(db: &impl SqliteLike, user_uuid: &str) -> Result<User, UserOpError> {
+        let mut results = lock_db_read!()
+            .and_then(|_| users.filter(uuid.eq(user_uuid))
+                .limit(1)
+                .load::<UserQuery>(db)
+                .map_err(|_| UserOpError::new("Database error")))?;
+        if results.is_empty() {
+            Result::Err(UserOpError::new("No matching user found"))
+        } else {
+            Result::Ok(results.remove(0).into()) // Take ownership, kill the stupid Vec
+        }
+    }
+
+    pub fn all_ids(db: &impl SqliteLike) -> Result<Vec<i32>, UserOpError> {
+        lock_db_read!()
+            .and_then(|_| users.select(id)
+                .load::<i32>(db)
+                .map_err(|_| UserOpError::new("Database error")))
+    }
+
+    pub fn all(db: &impl SqliteLike) -> Result<Vec<User>, UserOpError> {
+        lock_db_read!()
+            .and_then(|_| users.order(id.asc())
+                .load::<UserQuery>(db)
+                .map(|v| v.into_iter().map(|x| x.into()).collect())
+                .map_err(|_| UserOpError::new("Database error")))
+    }
+
+    // Record that the user has just finished a sync
+    // Callers should hold the per-user lock of items_sync
+    pub fn touch_last_sync(&self, db: &impl SqliteLike) -> Result<(), UserOpError> {
+        lock_db_write!()
+            .and_then(|_| diesel::update(users.find(self.id))
+                .set(last_sync.eq(Utc::now().naive_utc()))
+                .execute(db)
+                .map(|_| ())
+                .map_err(|_| UserOpError::new("Database error")))
+    }
+
+    // Refuses tokens older than `lifetime`, if any
+    // Also returns when the token expires, if ever
+    pub fn find_user_by_token(
+        db: &impl SqliteLike, token: &str, lifetime: Option<chrono::Duration>
+    ) -> Result<(User, Option<NaiveDateTime>), UserOpError> {
+        let (uid, issued_at) = crate::tokens::Token::find_token_by_id(db, token)
+            .ok_or_else(|| UserOpError::new("Invalid token"))?;
+        let now = Utc::now().naive_utc();
+        // Tokens without a timestamp are taken as the oldest (like when evicting them)
+        let expires_at = lifetime.map(|lifetime| issued_at
+            .map(|t| t + lifetime)
+            .unwrap_or(now));
+        if expires_at.map(|t| t <= now).unwrap_or(false) {
+            return Err(UserOpError::new("Token expired"));
+        }
+        Self::find_user_by_id(db, uid).map(|u| (u, expires_at))
     }
 
     // Create a JWT token for the current user if password matches
-    pub fn create_token(&self, db: &impl SqliteLike, passwd: &str) -> Result<String, UserOpError> {
+    pub fn create_token(
+        &self, db: &impl SqliteLike, passwd: &str,
+        max_sessions: Option<i64>
+    ) -> Result<String, UserOpError> {
         if self.password != passwd {
             Err(UserOpError::new("Password mismatch"))
+        } else if self.suspended {
+            Err(UserOpError::new("Account suspended"))
         } else {
-             crate::tokens::Token::create_token(db, self.id)
-                .ok_or("Failed to generate token".into())
+             crate::tokens::Token::create_token(db, self.id, max_sessions)
+                .map_err(UserOpError)
         }
     }
 
+    // Reinstating a deleted account also cancels its deletion (see soft_delete),
+    // or it would still be purged from under its user later
+    pub fn set_suspended(db: &impl SqliteLike, user_email: &str, suspend: bool) -> Result<(), UserOpError> {
+        let updated = lock_db_write!()
+            .and_then(|_| {
+                let target = users.filter(email.eq(user_email));
+                if suspend {
+                    diesel::update(target)
+                        .set(suspended.eq(true))
+                        .execute(db)
+                } else {
+                    diesel::update(target)
+                        .set((suspended.eq(false), deleted_at.eq(None::<NaiveDateTime>)))
+                        .execute(db)
+                }.map_err(|_| UserOpError::new("Database error"))
+            })?;
+        if updated == 0 {
+            Err(UserOpError::new("No matching user found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Delete the account, keeping its data until it is purged
+    // (see ACCOUNT_RETENTION_DAYS) unless an admin restores it first
+    pub fn soft_delete(&self, db: &impl SqliteLike) -> Result<(), UserOpError> {
+        lock_db_write!()
+            .and_then(|_| db.transaction::<_, diesel::result::Error, _>(|| {
+                diesel::update(users.find(self.id))
+                    .set((suspended.eq(true), deleted_at.eq(Utc::now().naive_utc())))
+                    .execute(db)?;
+                crate::tokens::Token::revoke_all(db, self.id)
+            }).map_err(|_| UserOpError::new("Database error")))
+    }
+
+    // Undo soft_delete, if the account has not been purged yet
+    pub fn restore(db: &impl SqliteLike, user_email: &str) -> Result<(), UserOpError> {
+        let updated = lock_db_write!()
+            .and_then(|_| diesel::update(users.filter(email.eq(user_email).and(deleted_at.is_not_null())))
+                .set((suspended.eq(false), deleted_at.eq(None::<NaiveDateTime>)))
+                .execute(db)
+                .map_err(|_| UserOpError::new("Database error")))?;
+        if updated == 0 {
+            Err(UserOpError::new("No matching deleted user found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    // IDs of users who deleted their account before `cutoff`
+    pub fn deleted_before(db: &impl SqliteLike, cutoff: NaiveDateTime) -> Result<Vec<i32>, UserOpError> {
+        lock_db_read!()
+            .and_then(|_| users.filter(deleted_at.lt(cutoff))
+                .select(id)
+                .load::<i32>(db)
+                .map_err(|_| UserOpError::new("Database error")))
+    }
+
+    // Remove the user along with all items and tokens for good
+    // Callers should hold the per-user lock of items_sync
+    pub fn purge(&self, db: &impl SqliteLike) -> Result<(), UserOpError> {
+        use crate::schema::{items, large_contents, notice_acks, tokens};
+        lock_db_write!()
+            .and_then(|_| db.transaction::<_, diesel::result::Error, _>(|| {
+                diesel::delete(items::table.filter(items::owner.eq(self.id))).execute(db)?;
+                diesel::delete(large_contents::table.filter(large_contents::owner.eq(self.id))).execute(db)?;
+                diesel::delete(notice_acks::table.filter(notice_acks::user_id.eq(self.id))).execute(db)?;
+                diesel::delete(tokens::table.filter(tokens::uid.eq(self.id))).execute(db)?;
+                diesel::delete(users.find(self.id)).execute(db).map(|_| ())
+            }).map_err(|_| UserOpError::new("Database error")))
+    }
+
+    // Change the password and all key parameters, and sign out every
+    // session, all in one transaction (like "change password" in the clients)
+    // The current password has to be checked by the caller
+    pub fn rotate(
+        &self, db: &impl SqliteLike, new_passwd: &str, key_params: &KeyParams
+    ) -> Result<(), UserOpError> {
+        let hashed: String = Password::new(new_passwd).into();
+        lock_db_write!()
+            .and_then(|_| db.transaction::<_, diesel::result::Error, _>(|| {
+                diesel::update(users.find(self.id))
+                    .set((password.eq(hashed), key_params))
+                    .execute(db)?;
+                crate::tokens::Token::revoke_all(db, self.id)
+            }).map_err(|_| UserOpError::new("Database error")))
+    }
+
     // Change the password in database, if old password is provided
+    // New key parameters, if any, are updated in the same statement
     // The current instance of User model will not be mutated
-    pub fn change_pw(&self, db: &impl SqliteLike, passwd: &str, new_passwd: &str) -> Result<(), UserOpError> {
+    pub fn change_pw(
+        &self, db: &impl SqliteLike, passwd: &str, new_passwd: &str,
+        key_params: &KeyParams
+    ) -> Result<(), UserOpError> {
         if self.password != passwd {
             Err(UserOpError::new("Password mismatch"))
         } else {
@@ -188,7 +432,7 @@ impl User {
             //      maybe we can record when the user last changed?
             lock_db_write!()
                 .and_then(|_| diesel::update(users.find(self.id))
-                    .set(password.eq::<String>(Password::new(new_passwd).into()))
+                    .set((password.eq::<String>(Password::new(new_passwd).into()), key_params))
                     .execute(db)
                     .map(|_| ())
                     .map_err(|_| UserOpError::new("Database error")))
@@ -198,25 +442,142 @@ impl User {
 
 // Implement request guard for User type
 // This is intended for protecting authorized endpoints
+impl User {
+    fn bearer_token<'a>(request: &'a request::Request) -> Result<&'a str, UserOpError> {
+        let token = request.headers().get_one("authorization");
+        match token {
+            None => Err(UserOpError::new("Token missing")),
+            Some(token) if !token.starts_with("Bearer ") => Err(UserOpError::new("Malformed Token")),
+            Some(token) => Ok(&token[7..])
+        }
+    }
+
+    // Returns when the token expires, too (see TOKEN_LIFETIME_DAYS)
+    fn find_user_by_auth_header(request: &request::Request) -> Result<(User, Option<NaiveDateTime>), UserOpError> {
+        let token = Self::bearer_token(request)?;
+        let config = request.guard::<State<Config>>().unwrap();
+        let lifetime = config.token_lifetime_days.map(chrono::Duration::days);
+        match request.guard::<crate::DbConn>().succeeded() {
+            Some(db) => Self::find_user_by_token(&db.0, token, lifetime),
+            // The pool may run dry under load
+            None => Err(UserOpError::new(DATABASE_UNAVAILABLE))
+        }
+    }
+
+    // Hand out a new token along with the response (see RefreshedToken)
+    // if the one in use expires soon
+    // Failing to do so is no reason to fail the request.
+    fn refresh_expiring_token(request: &request::Request, u: &User, expires_at: NaiveDateTime) {
+        let config = request.guard::<State<Config>>().unwrap();
+        let window = match config.token_refresh_window_hours {
+            Some(hours) => chrono::Duration::hours(hours),
+            None => return
+        };
+        if expires_at - Utc::now().naive_utc() > window {
+            return;
+        }
+
+        let old = match Self::bearer_token(request) {
+            Ok(old) => old,
+            Err(_) => return
+        };
+        let db = match request.guard::<crate::DbConn>().succeeded() {
+            Some(db) => db,
+            None => return
+        };
+        let refreshed = request.guard::<State<crate::tokens::RefreshedTokens>>().unwrap();
+        // Without evicting anything: the oldest session is most likely the one
+        // being refreshed, whose client has not picked up the new token yet.
+        // MAX_SESSIONS_PER_USER is enforced again at the next sign-in.
+        let res = refreshed.get_or_create(old, expires_at, ||
+            crate::tokens::Token::create_token(&db.0, u.id, None));
+        match res {
+            Ok(new) => {
+                request.local_cache(|| RefreshedToken(Some(new)));
+            },
+            Err(e) => eprintln!("Cannot refresh token of user {}: {}", u.id, e)
+        }
+    }
+}
+
+// A new token for the client to use from now on, sent in the
+// X-Refreshed-Token header of successful responses
+pub struct RefreshedToken(pub Option<String>);
+
 impl<'a, 'r> request::FromRequest<'a, 'r> for User {
     type Error = UserOpError;
 
     fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let token = request.headers().get_one("authorization");
-        match token {
-            None => request::Outcome::Failure((Status::Unauthorized, "Token missing".into())),
-            Some(token) => {
-                if !token.starts_with("Bearer ") {
-                    return request::Outcome::Failure((Status::Unauthorized, "Malformed Token".into()));
+        match Self::find_user_by_auth_header(request) {
+            // Existing tokens of suspended users stop working, too
+            Ok((ref u, _)) if u.suspended => {
+                request.local_cache(|| crate::api::GuardError {
+                    error: "Account suspended".into(),
+                    code: Some(ACCOUNT_SUSPENDED)
+                });
+                request::Outcome::Failure((Status::Forbidden, UserOpError::new("Account suspended")))
+            },
+            Ok((u, expires_at)) => {
+                // The permit lives in the request-local cache, and thus is
+                // released once the request is done. Caching also means that
+                // a request using this guard twice only takes one permit.
+                let concurrency = request.guard::<State<UserConcurrency>>().unwrap();
+                let permit = request.local_cache(|| concurrency.try_acquire(u.id));
+                if permit.is_none() {
+                    request.local_cache(|| crate::api::GuardError {
+                        error: "Too many concurrent requests".into(),
+                        code: None
+                    });
+                    return request::Outcome::Failure((Status::TooManyRequests,
+                        UserOpError::new("Too many concurrent requests")));
                 }
-
-                let result = Self::find_user_by_token(
-                    &request.guard::<crate::DbConn>().unwrap().0, &token[7..]);
-                match result {
-                    Ok(u) => request::Outcome::Success(u),
-                    Err(err) => request::Outcome::Failure((Status::Unauthorized, err))
+                if let Some(expires_at) = expires_at {
+                    Self::refresh_expiring_token(request, &u, expires_at);
                 }
+                request::Outcome::Success(u)
+            },
+            // Not the client's fault; a 401 would sign them out
+            Err(ref err) if err.0 == DATABASE_UNAVAILABLE => {
+                request.local_cache(|| crate::api::GuardError {
+                    error: err.0.clone(),
+                    code: None
+                });
+                request::Outcome::Failure((Status::ServiceUnavailable, UserOpError::new(DATABASE_UNAVAILABLE)))
+            },
+            Err(err) => {
+                // Leave the reason for our catcher (see api::GuardError)
+                request.local_cache(|| crate::api::GuardError {
+                    error: err.0.clone(),
+                    code: None
+                });
+                request::Outcome::Failure((Status::Unauthorized, err))
             }
         }
     }
+}
+
+// Users listed in ADMIN_EMAILS
+pub struct Admin;
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for Admin {
+    type Error = UserOpError;
+
+    fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let u = match request.guard::<User>() {
+            request::Outcome::Success(u) => u,
+            request::Outcome::Failure(f) => return request::Outcome::Failure(f),
+            request::Outcome::Forward(f) => return request::Outcome::Forward(f)
+        };
+
+        let config = request.guard::<State<Config>>().unwrap();
+        if config.admin_emails.contains(&u.email) {
+            request::Outcome::Success(Admin)
+        } else {
+            request.local_cache(|| crate::api::GuardError {
+                error: "Admin access required".into(),
+                code: None
+            });
+            request::Outcome::Failure((Status::Forbidden, UserOpError::new("Admin access required")))
+        }
+    }
 }
\ No newline at end of file